@@ -0,0 +1,15 @@
+-- Replace the free-form road_state column with a Postgres enum.
+-- Historic values are matched case-insensitively; anything else becomes UNKNOWN.
+CREATE TYPE road_state AS ENUM ('NORMAL', 'SMALL_PITS', 'LARGE_PITS', 'BUMP', 'UNKNOWN');
+
+ALTER TABLE processed_agent_data
+    ALTER COLUMN road_state TYPE road_state
+    USING (
+        CASE UPPER(TRIM(road_state))
+            WHEN 'NORMAL' THEN 'NORMAL'
+            WHEN 'SMALL_PITS' THEN 'SMALL_PITS'
+            WHEN 'LARGE_PITS' THEN 'LARGE_PITS'
+            WHEN 'BUMP' THEN 'BUMP'
+            ELSE 'UNKNOWN'
+        END
+    )::road_state;
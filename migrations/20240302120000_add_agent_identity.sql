@@ -0,0 +1,11 @@
+-- Track which agent (vehicle/device) and, optionally, which user produced each reading.
+-- Rows recorded before agents were identified are attributed to agent 0.
+ALTER TABLE processed_agent_data
+    ADD COLUMN agent_id INTEGER NOT NULL DEFAULT 0,
+    ADD COLUMN user_id INTEGER;
+
+ALTER TABLE processed_agent_data
+    ALTER COLUMN agent_id DROP DEFAULT;
+
+CREATE INDEX processed_agent_data_agent_id_timestamp_idx
+    ON processed_agent_data (agent_id, timestamp DESC);
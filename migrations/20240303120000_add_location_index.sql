@@ -0,0 +1,3 @@
+-- Index the reading locations for 2D range scans (`point(longitude, latitude) <@ box(...)`).
+CREATE INDEX processed_agent_data_location_idx
+    ON processed_agent_data USING gist (point(longitude, latitude));
@@ -0,0 +1,3 @@
+-- Support keyset pagination over `(timestamp, id)`, newest first.
+CREATE INDEX processed_agent_data_timestamp_id_idx
+    ON processed_agent_data (timestamp DESC, id DESC);
@@ -0,0 +1,7 @@
+-- Deleted rows are kept in the trash until purged, so that mistaken deletes can be restored.
+ALTER TABLE processed_agent_data
+    ADD COLUMN deleted_at TIMESTAMPTZ;
+
+CREATE INDEX processed_agent_data_deleted_at_idx
+    ON processed_agent_data (deleted_at DESC)
+    WHERE deleted_at IS NOT NULL;
@@ -0,0 +1,55 @@
+-- Keep the previous values of every updated or deleted row, for auditing.
+-- The actor is taken from the `lab2.actor` setting of the transaction, if set.
+CREATE TYPE history_operation AS ENUM ('UPDATE', 'DELETE', 'RESTORE', 'PURGE');
+
+CREATE TABLE processed_agent_data_history(
+    revision BIGSERIAL PRIMARY KEY NOT NULL,
+    id INTEGER NOT NULL,
+    operation history_operation NOT NULL,
+    changed_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    actor TEXT,
+    agent_id INTEGER NOT NULL,
+    user_id INTEGER,
+    road_state road_state NOT NULL,
+    x FLOAT NOT NULL,
+    y FLOAT NOT NULL,
+    z FLOAT NOT NULL,
+    latitude FLOAT NOT NULL,
+    longitude FLOAT NOT NULL,
+    timestamp TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX processed_agent_data_history_id_idx
+    ON processed_agent_data_history (id, revision DESC);
+
+CREATE FUNCTION record_processed_agent_data_history() RETURNS TRIGGER AS $$
+DECLARE
+    operation history_operation;
+BEGIN
+    IF TG_OP = 'DELETE' THEN
+        operation := 'PURGE';
+    ELSIF OLD IS NOT DISTINCT FROM NEW THEN
+        RETURN NULL;
+    ELSIF OLD.deleted_at IS NULL AND NEW.deleted_at IS NOT NULL THEN
+        operation := 'DELETE';
+    ELSIF OLD.deleted_at IS NOT NULL AND NEW.deleted_at IS NULL THEN
+        operation := 'RESTORE';
+    ELSE
+        operation := 'UPDATE';
+    END IF;
+
+    INSERT INTO processed_agent_data_history
+        (id, operation, actor, agent_id, user_id, road_state, x, y, z, latitude, longitude, timestamp)
+    VALUES (
+        OLD.id, operation, NULLIF(current_setting('lab2.actor', true), ''),
+        OLD.agent_id, OLD.user_id, OLD.road_state,
+        OLD.x, OLD.y, OLD.z, OLD.latitude, OLD.longitude, OLD.timestamp
+    );
+
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER processed_agent_data_history_trigger
+    AFTER UPDATE OR DELETE ON processed_agent_data
+    FOR EACH ROW EXECUTE FUNCTION record_processed_agent_data_history();
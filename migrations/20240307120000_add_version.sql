@@ -0,0 +1,18 @@
+-- Row version for optimistic concurrency, exposed as the `ETag` of the data.
+-- Bumped on every actual change of the row, so that no-op updates keep the version.
+ALTER TABLE processed_agent_data
+    ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
+
+CREATE FUNCTION bump_processed_agent_data_version() RETURNS TRIGGER AS $$
+BEGIN
+    IF OLD IS DISTINCT FROM NEW THEN
+        NEW.version := OLD.version + 1;
+    END IF;
+
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER processed_agent_data_version_trigger
+    BEFORE UPDATE ON processed_agent_data
+    FOR EACH ROW EXECUTE FUNCTION bump_processed_agent_data_version();
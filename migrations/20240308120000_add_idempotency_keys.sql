@@ -0,0 +1,11 @@
+-- Responses to the requests with an `Idempotency-Key`, replayed on their retries until expired.
+CREATE TABLE idempotency_keys(
+    key TEXT PRIMARY KEY NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    status SMALLINT NOT NULL,
+    location TEXT,
+    ids INTEGER[] NOT NULL
+);
+
+CREATE INDEX idempotency_keys_created_at_idx
+    ON idempotency_keys (created_at);
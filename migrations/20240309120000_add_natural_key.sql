@@ -0,0 +1,20 @@
+-- The same physical reading is identified by its timestamp, coordinates and accelerometer values.
+-- Already stored duplicates are moved to the trash, keeping the earliest of them.
+UPDATE processed_agent_data
+SET deleted_at = now()
+WHERE id IN (
+    SELECT id
+    FROM (
+        SELECT id, row_number() OVER (
+            PARTITION BY timestamp, latitude, longitude, x, y, z
+            ORDER BY id
+        ) AS occurrence
+        FROM processed_agent_data
+        WHERE deleted_at IS NULL
+    ) AS occurrences
+    WHERE occurrence > 1
+);
+
+CREATE UNIQUE INDEX processed_agent_data_natural_key_idx
+    ON processed_agent_data (timestamp, latitude, longitude, x, y, z)
+    WHERE deleted_at IS NULL;
@@ -0,0 +1,3 @@
+-- Positions of the duplicate items in the request, replayed along with the rest of the response
+ALTER TABLE idempotency_keys
+    ADD COLUMN duplicates INTEGER[] NOT NULL DEFAULT '{}';
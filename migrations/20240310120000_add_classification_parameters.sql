@@ -0,0 +1,8 @@
+-- Road-state classification parameters, set at runtime in place of the configured ones.
+-- There is at most one row, with the parameters in use.
+CREATE TABLE classification_parameters(
+    id BOOLEAN PRIMARY KEY NOT NULL DEFAULT TRUE CHECK (id),
+    parameters JSONB NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    updated_by TEXT
+);
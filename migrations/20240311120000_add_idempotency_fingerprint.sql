@@ -0,0 +1,6 @@
+-- Hash of the request, that its retries with the same key must match.
+-- The keys stored before are dropped, as their requests are unknown.
+DELETE FROM idempotency_keys;
+
+ALTER TABLE idempotency_keys
+    ADD COLUMN fingerprint BYTEA NOT NULL;
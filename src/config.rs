@@ -1,15 +1,25 @@
 use std::net::{Ipv4Addr, SocketAddr, ToSocketAddrs};
+use std::num::{NonZeroU32, NonZeroU64, NonZeroUsize};
 use std::str::FromStr;
+use std::time::Duration;
 
 use color_eyre::eyre::{eyre, Context};
 use secrecy::{ExposeSecret, SecretString};
 use serde::Deserialize;
 use sqlx::postgres::PgConnectOptions;
 
+use crate::{control::ws::OverflowPolicy, data::DuplicatePolicy, service::classification};
+
 #[derive(Debug, Deserialize)]
 pub struct Configuration {
     database: Database,
     server: Server,
+    trash: Trash,
+    idempotency: Idempotency,
+    deduplication: Deduplication,
+    validation: Validation,
+    classification: classification::Parameters,
+    websocket: WebSocket,
 }
 
 #[derive(Debug, Deserialize)]
@@ -28,6 +38,58 @@ pub struct Server {
     port: u16,
 }
 
+/// Retention of the soft-deleted processed agent data
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct Trash {
+    /// How long deleted data is kept in the trash before being purged, in seconds
+    retention_secs: u64,
+    /// How often the trash is purged, in seconds
+    purge_interval_secs: NonZeroU64,
+}
+
+/// Retention of the responses to the requests with an `Idempotency-Key`
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct Idempotency {
+    /// How long a response is replayed on the retries of its request, in seconds
+    ttl_secs: u64,
+    /// How often the expired responses are purged, in seconds
+    purge_interval_secs: NonZeroU64,
+}
+
+/// Handling of the posted processed agent data, that duplicates the stored data
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct Deduplication {
+    policy: DuplicatePolicy,
+}
+
+/// Domain constraints of the incoming processed agent data
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct Validation {
+    /// How far in the future the timestamps may be, to tolerate unsynchronized clocks, in seconds
+    max_clock_skew_secs: u64,
+    /// Upper bound of the absolute accelerometer value along each axis
+    max_acceleration: f64,
+}
+
+/// Delivery of the processed agent data changes to the WebSocket subscribers
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct WebSocket {
+    /// How many messages may wait to be sent to a subscriber
+    queue_capacity: NonZeroUsize,
+    /// What to do with a message for a subscriber, whose queue is full
+    overflow: OverflowPolicy,
+    /// How many recent messages are kept to be replayed to the reconnecting subscribers
+    event_log_capacity: NonZeroUsize,
+    /// How often the subscribers are pinged, in seconds
+    heartbeat_interval_secs: NonZeroU64,
+    /// How many pings in a row a subscriber may leave unanswered, before it is disconnected
+    max_missed_pongs: NonZeroU32,
+    /// How long a subscriber may send nothing but pongs, before it is disconnected, in seconds,
+    /// or never, if absent
+    #[serde(default)]
+    idle_timeout_secs: Option<NonZeroU64>,
+}
+
 impl Configuration {
     pub fn try_read() -> color_eyre::Result<Self> {
         let base_path =
@@ -53,6 +115,31 @@ impl Configuration {
     pub fn server(&self) -> Server {
         self.server
     }
+
+    pub fn trash(&self) -> Trash {
+        self.trash
+    }
+
+    pub fn idempotency(&self) -> Idempotency {
+        self.idempotency
+    }
+
+    pub fn deduplication(&self) -> Deduplication {
+        self.deduplication
+    }
+
+    pub fn validation(&self) -> Validation {
+        self.validation
+    }
+
+    /// Road-state classification parameters, unless changed at runtime
+    pub fn classification(&self) -> &classification::Parameters {
+        &self.classification
+    }
+
+    pub fn websocket(&self) -> WebSocket {
+        self.websocket
+    }
 }
 
 impl Database {
@@ -66,6 +153,69 @@ impl Database {
     }
 }
 
+impl Trash {
+    pub fn retention(&self) -> Duration {
+        Duration::from_secs(self.retention_secs)
+    }
+
+    pub fn purge_interval(&self) -> Duration {
+        Duration::from_secs(self.purge_interval_secs.get())
+    }
+}
+
+impl Idempotency {
+    pub fn ttl(&self) -> Duration {
+        Duration::from_secs(self.ttl_secs)
+    }
+
+    pub fn purge_interval(&self) -> Duration {
+        Duration::from_secs(self.purge_interval_secs.get())
+    }
+}
+
+impl Deduplication {
+    pub fn policy(&self) -> DuplicatePolicy {
+        self.policy
+    }
+}
+
+impl Validation {
+    pub fn max_clock_skew(&self) -> Duration {
+        Duration::from_secs(self.max_clock_skew_secs)
+    }
+
+    pub fn max_acceleration(&self) -> f64 {
+        self.max_acceleration
+    }
+}
+
+impl WebSocket {
+    pub fn queue_capacity(&self) -> usize {
+        self.queue_capacity.get()
+    }
+
+    pub fn overflow(&self) -> OverflowPolicy {
+        self.overflow
+    }
+
+    pub fn event_log_capacity(&self) -> usize {
+        self.event_log_capacity.get()
+    }
+
+    pub fn heartbeat_interval(&self) -> Duration {
+        Duration::from_secs(self.heartbeat_interval_secs.get())
+    }
+
+    pub fn max_missed_pongs(&self) -> u32 {
+        self.max_missed_pongs.get()
+    }
+
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout_secs
+            .map(|secs| Duration::from_secs(secs.get()))
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum Environment {
     Local,
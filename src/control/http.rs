@@ -1,34 +1,54 @@
 use std::{
     fmt,
+    future::{ready, Ready},
     num::{NonZeroU32, NonZeroU8},
 };
 
 use actix_web::{
-    delete, get,
-    http::header,
-    post, put,
-    web::{Data, Json, Path, Query},
-    Either, HttpResponse,
+    delete, dev, get,
+    http::{
+        header::{self, EntityTag, HeaderName},
+        StatusCode,
+    },
+    patch, post, put,
+    web::{Data, Header, Json, Path, Query},
+    Either, FromRequest, HttpRequest, HttpResponse,
 };
-use serde::{Deserialize, Deserializer};
+use serde::{de::IntoDeserializer, Deserialize, Deserializer, Serialize};
+use sha2::{Digest, Sha256};
 use tracing::instrument;
 use utoipa::IntoParams;
 
 use crate::{
+    config::{Deduplication, Idempotency, Validation},
     control::ws,
-    data::{ProcessedAgent, ProcessedAgentId, ProcessedAgentWithId},
-    service,
+    data::{
+        self, Agent, AgentId, BoundingBox, Circle, Cursor, IdempotentResponse, MagnitudeRange,
+        Page, ProcessedAgent, ProcessedAgentCreation, ProcessedAgentDeletion, ProcessedAgentFilter,
+        ProcessedAgentId, ProcessedAgentList, ProcessedAgentPage, ProcessedAgentPatch,
+        ProcessedAgentReclassification, ProcessedAgentRevision, ProcessedAgentSelection,
+        ProcessedAgentWithId, RoadState, Sort, TimeRange, Validate, Version, VersionMatch,
+        Versioned,
+    },
+    error::{AppResult, Problem},
+    service::{
+        self,
+        classification::{self, Classification, Parameters},
+        Conditional, Creation,
+    },
 };
 
 /// Post a single/list of processed agent data and notify ws subscribers
 #[utoipa::path(
     path = "/api/processed-agent-data",
+    params(IdempotencyKey),
     request_body(
         content = Vec<ProcessedAgent>,
         description = "Processed agent(-s) data to post and notify ws subscribers about",
         examples(
             ("Single" = (
                 value = json!({
+                    "agent_id": 1,
                     "road_state": "NORMAL",
                     "accelerometer": {
                         "x": 0.0,
@@ -44,6 +64,7 @@ use crate::{
             )),
             ("List" = (
                 value = json!([{
+                    "agent_id": 1,
                     "road_state": "NORMAL",
                     "accelerometer": {
                         "x": 0.0,
@@ -60,67 +81,266 @@ use crate::{
         )
     ),
     responses(
-        (status = 200, description = "List is empty"),
+        (
+            status = 200,
+            body = ProcessedAgentCreation,
+            description = "List is empty or all of its items are duplicates",
+        ),
         (
             status = 201,
+            body = ProcessedAgentCreation,
             headers(("Location" = Vec<String>, description = "Locations of the created resources")),
         ),
         (status = 400, description = "Invalid request body"),
+        (
+            status = 422,
+            description = "Data violates the domain constraints, \
+                or the `Idempotency-Key` was already used for a different request"
+        ),
+        (
+            status = 409,
+            body = ProcessedAgentCreation,
+            description = "Nothing was stored, because of the duplicates under the `reject` policy",
+        ),
         (status = "5XX", description = "Internal server error")
     )
 )]
 #[post("/processed-agent-data")]
 #[instrument(skip(subs, pool))]
+#[allow(clippy::too_many_arguments)]
 pub async fn create_processed_agent_data(
+    req: HttpRequest,
     data: Either<Json<ProcessedAgent>, Json<Vec<ProcessedAgent>>>,
+    idempotency_key: IdempotencyKey,
+    idempotency: Data<Idempotency>,
+    deduplication: Data<Deduplication>,
+    validation: Data<Validation>,
+    subs: Data<ws::Subscribers>,
+    pool: Data<sqlx::PgPool>,
+) -> actix_web::Result<HttpResponse> {
+    let (data, single) = validated_list(data, **validation)?;
+    let policy = deduplication.policy();
+    let respond = |creation: &Creation| created_response(creation, single);
+    let response = match idempotency_key.request(&req, &data, single)? {
+        Some(IdempotentRequest { key, fingerprint }) => {
+            service::create_processed_agent_data_list_idempotently(
+                &key,
+                &fingerprint,
+                idempotency.ttl(),
+                data,
+                policy,
+                respond,
+                &subs,
+                &pool,
+            )
+            .await?
+        }
+        None => {
+            let creation =
+                service::create_processed_agent_data_list(data, policy, &subs, &pool).await?;
+            respond(&creation)?
+        }
+    };
+    created(response)
+}
+
+/// Post a single/list of raw agent data, classify the road state of it,
+/// then store and notify ws subscribers about the resulting processed agent data
+#[utoipa::path(
+    path = "/api/agent-data",
+    params(IdempotencyKey),
+    request_body(
+        content = Vec<Agent>,
+        description = "Agent(-s) data to classify, post and notify ws subscribers about",
+        examples(
+            ("Single" = (
+                value = json!({
+                    "agent_id": 1,
+                    "accelerometer": {
+                        "x": 0.0,
+                        "y": 0.0,
+                        "z": 9.8
+                    },
+                    "gps": {
+                        "latitude": 0.0,
+                        "longitude": 0.0
+                    },
+                    "timestamp": "2023-10-01T00:00:00Z"
+                })
+            )),
+            ("List" = (
+                value = json!([{
+                    "agent_id": 1,
+                    "accelerometer": {
+                        "x": 0.0,
+                        "y": 0.0,
+                        "z": 9.8
+                    },
+                    "gps": {
+                        "latitude": 0.0,
+                        "longitude": 0.0
+                    },
+                    "timestamp": "2023-10-01T00:00:00Z"
+                }])
+            )),
+        )
+    ),
+    responses(
+        (
+            status = 200,
+            body = ProcessedAgentCreation,
+            description = "List is empty or all of its items are duplicates",
+        ),
+        (
+            status = 201,
+            body = ProcessedAgentCreation,
+            headers(("Location" = Vec<String>, description = "Locations of the created processed agent data")),
+        ),
+        (status = 400, description = "Invalid request body"),
+        (
+            status = 422,
+            description = "Data violates the domain constraints, \
+                or the `Idempotency-Key` was already used for a different request"
+        ),
+        (
+            status = 409,
+            body = ProcessedAgentCreation,
+            description = "Nothing was stored, because of the duplicates under the `reject` policy",
+        ),
+        (status = "5XX", description = "Internal server error")
+    )
+)]
+#[post("/agent-data")]
+#[instrument(skip(subs, pool))]
+#[allow(clippy::too_many_arguments)]
+pub async fn create_agent_data(
+    req: HttpRequest,
+    data: Either<Json<Agent>, Json<Vec<Agent>>>,
+    idempotency_key: IdempotencyKey,
+    idempotency: Data<Idempotency>,
+    deduplication: Data<Deduplication>,
+    validation: Data<Validation>,
+    classification: Data<Classification>,
     subs: Data<ws::Subscribers>,
     pool: Data<sqlx::PgPool>,
 ) -> actix_web::Result<HttpResponse> {
-    let result = match data {
-        Either::Right(Json(data)) if data.is_empty() => HttpResponse::Ok().finish(),
-        Either::Right(Json(data)) if data.len() == 1 => {
-            let [data] = unsafe { <[_; 1] as TryFrom<Vec<_>>>::try_from(data).unwrap_unchecked() };
-            let id = service::create_processed_agent_data(data, &subs, &pool).await?;
-            HttpResponse::Created()
-                .append_header((
-                    header::LOCATION,
-                    format!(r#"["/api/processed-agent-data/{id}"]"#),
-                ))
-                .finish()
+    let (data, single) = validated_list(data, **validation)?;
+    let classifier = classification.current();
+    let policy = deduplication.policy();
+    let respond = |creation: &Creation| created_response(creation, single);
+    let response = match idempotency_key.request(&req, &data, single)? {
+        Some(IdempotentRequest { key, fingerprint }) => {
+            service::create_agent_data_list_idempotently(
+                &key,
+                &fingerprint,
+                idempotency.ttl(),
+                data,
+                &*classifier,
+                policy,
+                respond,
+                &subs,
+                &pool,
+            )
+            .await?
+        }
+        None => {
+            let creation =
+                service::create_agent_data_list(data, &*classifier, policy, &subs, &pool).await?;
+            respond(&creation)?
         }
+    };
+    created(response)
+}
+
+/// Validated items of a body, posted either as a single one or as a list,
+/// along with whether it was a single one
+fn validated_list<T: Validate>(
+    data: Either<Json<T>, Json<Vec<T>>>,
+    validation: Validation,
+) -> actix_web::Result<(Vec<T>, bool)> {
+    Ok(match data {
         Either::Left(Json(data)) => {
-            let id = service::create_processed_agent_data(data, &subs, &pool).await?;
-            HttpResponse::Created()
-                .append_header((header::LOCATION, format!("/api/processed-agent-data/{id}")))
-                .finish()
+            data::validate(&data, validation)?;
+            (vec![data], true)
         }
         Either::Right(Json(data)) => {
-            let ids = service::create_processed_agent_data_list(data, &subs, &pool).await?;
-            let mut response = HttpResponse::Created();
-            response.append_header((
-                header::LOCATION,
-                serde_json::to_string(
-                    &ids.into_iter()
-                        .map(|id| format!("/api/processed-agent-data/{id}"))
-                        .collect::<Vec<_>>(),
-                )?,
-            ));
-            response.finish()
+            data::validate(&data, validation)?;
+            (data, false)
+        }
+    })
+}
+
+/// Response to the creation of the processed agent data, as stored or replayed
+fn created(response: IdempotentResponse) -> actix_web::Result<HttpResponse> {
+    let status = StatusCode::from_u16(response.status)
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    let mut result = HttpResponse::build(status);
+    if let Some(location) = response.location {
+        result.append_header((header::LOCATION, location));
+    }
+    Ok(result.json(ProcessedAgentCreation {
+        ids: response.ids,
+        duplicates: response.duplicates,
+    }))
+}
+
+/// Response to the creation of the processed agent data,
+/// posted either as a single one or as a list
+fn created_response(creation: &Creation, single: bool) -> AppResult<IdempotentResponse> {
+    let stored = match creation {
+        Creation::Stored(stored) => stored,
+        Creation::Rejected(duplicates) => {
+            return Ok(IdempotentResponse {
+                status: StatusCode::CONFLICT.as_u16(),
+                location: None,
+                ids: Vec::new(),
+                duplicates: duplicates.clone(),
+            })
         }
     };
-    Ok(result)
+
+    let created: Vec<_> = stored
+        .iter()
+        .filter(|stored| !stored.duplicate)
+        .map(|stored| format!("/api/processed-agent-data/{}", stored.id))
+        .collect();
+    let (status, location) = match created.as_slice() {
+        [] => (StatusCode::OK, None),
+        [location] if single => (StatusCode::CREATED, Some(location.clone())),
+        locations => (StatusCode::CREATED, Some(serde_json::to_string(locations)?)),
+    };
+
+    Ok(IdempotentResponse {
+        status: status.as_u16(),
+        location,
+        ids: stored.iter().map(|stored| stored.id).collect(),
+        duplicates: (0..)
+            .zip(stored)
+            .filter(|(_, stored)| stored.duplicate)
+            .map(|(i, _)| i)
+            .collect(),
+    })
 }
 
 /// Read a single processed agent data by ID
 #[utoipa::path(
     path = "/api/processed-agent-data/{id}",
-    params(ProcessedAgentId),
+    params(
+        ProcessedAgentId,
+        (
+            "If-None-Match" = Option<String>,
+            Header,
+            description = "Skip the body if the current `ETag` of the data is one of these"
+        ),
+    ),
     responses(
         (
             status = 200,
             body = ProcessedAgent,
             description = "A single processed agent data, corresponding to the given id",
+            headers(("ETag" = String, description = "Current version of the data")),
             example = json!({
+                "agent_id": 1,
                 "road_state": "NORMAL",
                 "accelerometer": {
                     "x": 0.0,
@@ -134,6 +354,7 @@ pub async fn create_processed_agent_data(
                 "timestamp": "2023-10-01T00:00:00Z"
             }),
         ),
+        (status = 304, description = "Processed agent data was not modified since the given `ETag`"),
         (status = 400, description = "Invalid ID"),
         (status = 404, description = "Processed agent data not found"),
         (status = "5XX", description = "Internal server error")
@@ -143,39 +364,295 @@ pub async fn create_processed_agent_data(
 #[instrument(skip(pool))]
 pub async fn read_processed_agent_data(
     id: Path<ProcessedAgentId>,
+    if_none_match: Header<header::IfNoneMatch>,
     pool: Data<sqlx::PgPool>,
-) -> actix_web::Result<Option<Json<ProcessedAgent>>> {
+) -> actix_web::Result<HttpResponse> {
     let result = service::fetch_processed_agent_data(id.into_inner(), &pool).await?;
-    Ok(result.map(Json))
+    let Some(Versioned { data, version }) = result else {
+        return Ok(HttpResponse::NotFound().finish());
+    };
+    let etag = header::ETag(entity_tag(version));
+    let not_modified = version_none_match(if_none_match.into_inner())
+        .is_some_and(|if_none_match| if_none_match.matches(version));
+    Ok(if not_modified {
+        HttpResponse::NotModified().insert_header(etag).finish()
+    } else {
+        HttpResponse::Ok().insert_header(etag).json(data)
+    })
 }
 
 /// Read a list of processed agent data
 #[utoipa::path(
     path = "/api/processed-agent-data",
-    params(Pagination),
+    params(Pagination, PageCursor, Sorting, ListFormat, TimeRange, RoadStates, MagnitudeRange),
     responses(
         (
             status = 200,
-            body = Vec<ProcessedAgentWithId>,
-            description = "List of processed agent data"
+            body = ProcessedAgentList,
+            description = "List of processed agent data",
+            headers(
+                ("X-Total-Count" = u64, description = "Total number of items, matching the filters"),
+                (
+                    "Link" = String,
+                    description = "Links to the `first`, `prev`, `next` and `last` pages. \
+                        Only `first` and `next` are given when paging by cursor"
+                ),
+            ),
         ),
-        (status = 400, description = "Invalid pagination parameters"),
+        (status = 400, description = "Invalid pagination, sorting or filtering parameters"),
         (status = "5XX", description = "Internal server error")
     )
 )]
 #[get("/processed-agent-data")]
 #[instrument(skip(pool))]
 pub async fn read_processed_agent_data_list(
+    req: HttpRequest,
+    query: ListQuery,
+    filters: Filters,
+    pool: Data<sqlx::PgPool>,
+) -> actix_web::Result<HttpResponse> {
+    let filter = filters.into_filter(None);
+    let page = query.start();
+    let size = query.pagination.size;
+    let (items, total) = tokio::try_join!(
+        service::fetch_processed_agent_data_list(page, size.0, query.sorting.sort, &filter, &pool),
+        service::count_processed_agent_data(&filter, &pool),
+    )?;
+    list_response(&req, page, size, &query.format, items, total)
+}
+
+/// Read a list of processed agent data, produced by a single agent
+#[utoipa::path(
+    path = "/api/agents/{agent_id}/processed-agent-data",
+    params(
+        AgentId,
+        Pagination,
+        PageCursor,
+        Sorting,
+        ListFormat,
+        TimeRange,
+        RoadStates,
+        MagnitudeRange
+    ),
+    responses(
+        (
+            status = 200,
+            body = ProcessedAgentList,
+            description = "List of processed agent data, produced by the given agent",
+            headers(
+                ("X-Total-Count" = u64, description = "Total number of items, matching the filters"),
+                (
+                    "Link" = String,
+                    description = "Links to the `first`, `prev`, `next` and `last` pages. \
+                        Only `first` and `next` are given when paging by cursor"
+                ),
+            ),
+        ),
+        (status = 400, description = "Invalid agent ID, pagination, sorting or filtering parameters"),
+        (status = "5XX", description = "Internal server error")
+    )
+)]
+#[get("/agents/{agent_id}/processed-agent-data")]
+#[instrument(skip(pool))]
+pub async fn read_agent_processed_agent_data_list(
+    req: HttpRequest,
+    agent_id: Path<AgentId>,
+    query: ListQuery,
+    filters: Filters,
+    pool: Data<sqlx::PgPool>,
+) -> actix_web::Result<HttpResponse> {
+    let filter = filters.into_filter(Some(agent_id.into_inner()));
+    let page = query.start();
+    let size = query.pagination.size;
+    let (items, total) = tokio::try_join!(
+        service::fetch_processed_agent_data_list(page, size.0, query.sorting.sort, &filter, &pool),
+        service::count_processed_agent_data(&filter, &pool),
+    )?;
+    list_response(&req, page, size, &query.format, items, total)
+}
+
+/// Respond with a page of the list, along with the total number of items
+/// and the links to the neighbouring pages
+fn list_response(
+    req: &HttpRequest,
+    page: Page,
+    size: PageSize,
+    format: &ListFormat,
+    items: Vec<ProcessedAgentWithId>,
+    total: u64,
+) -> actix_web::Result<HttpResponse> {
+    const X_TOTAL_COUNT: HeaderName = HeaderName::from_static("x-total-count");
+
+    let mut links = Vec::with_capacity(4);
+    match page {
+        Page::Number(number) => {
+            let number = number.get() as u64;
+            let last = total.div_ceil(size.0.get() as u64).max(1);
+            links.push(("first", link_with(req, &[("page", 1.to_string())])?));
+            if number > 1 {
+                let prev = (number - 1).min(last);
+                links.push(("prev", link_with(req, &[("page", prev.to_string())])?));
+            }
+            if number < last {
+                let next = number + 1;
+                links.push(("next", link_with(req, &[("page", next.to_string())])?));
+            }
+            links.push(("last", link_with(req, &[("page", last.to_string())])?));
+        }
+        Page::After(_) => {
+            links.push(("first", link_with(req, &[("after", String::new())])?));
+            let next = items
+                .last()
+                .and_then(ProcessedAgentWithId::cursor)
+                .filter(|_| items.len() == size.0.get() as usize);
+            if let Some(next) = next {
+                links.push(("next", link_with(req, &[("after", next.to_string())])?));
+            }
+        }
+    }
+    let links = links
+        .into_iter()
+        .map(|(rel, link)| format!(r#"<{link}>; rel="{rel}""#))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let body = if format.envelope {
+        ProcessedAgentList::Envelope(ProcessedAgentPage {
+            items,
+            page: match page {
+                Page::Number(number) => Some(number.get()),
+                Page::After(_) => None,
+            },
+            size: size.0.get(),
+            total,
+        })
+    } else {
+        ProcessedAgentList::Items(items)
+    };
+
+    Ok(HttpResponse::Ok()
+        .insert_header((X_TOTAL_COUNT, total))
+        .insert_header((header::LINK, links))
+        .json(body))
+}
+
+/// Link to the requested resource, but starting from another page
+fn link_with(
+    req: &HttpRequest,
+    params: &[(&str, String)],
+) -> Result<String, serde_urlencoded::ser::Error> {
+    let mut query: Vec<(String, String)> =
+        serde_urlencoded::from_str(req.query_string()).unwrap_or_default();
+    query.retain(|(key, _)| key != "page" && key != "after");
+    query.extend(
+        params
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.clone())),
+    );
+
+    Ok(format!(
+        "{}?{}",
+        req.path(),
+        serde_urlencoded::to_string(query)?
+    ))
+}
+
+/// Search for the processed agent data, located inside the given area
+#[utoipa::path(
+    path = "/api/processed-agent-data/search",
+    params(Area, Pagination, TimeRange, RoadStates, MagnitudeRange),
+    responses(
+        (
+            status = 200,
+            body = Vec<ProcessedAgentWithId>,
+            description = "List of processed agent data, located inside the given area"
+        ),
+        (status = 400, description = "Invalid bounding box, pagination or filtering parameters"),
+        (status = "5XX", description = "Internal server error")
+    )
+)]
+#[get("/processed-agent-data/search")]
+#[instrument(skip(pool))]
+pub async fn search_processed_agent_data(
+    area: Query<Area>,
     pagination: Query<Pagination>,
+    filters: Filters,
     pool: Data<sqlx::PgPool>,
 ) -> actix_web::Result<Json<Vec<ProcessedAgentWithId>>> {
-    let result =
-        service::fetch_processed_agent_data_list(pagination.page.0, pagination.size.0, &pool)
-            .await?;
+    let filter = filters.into_filter(None);
+    let result = service::search_processed_agent_data_in_area(
+        area.bbox,
+        pagination.page.0,
+        pagination.size.0,
+        &filter,
+        &pool,
+    )
+    .await?;
     Ok(Json(result))
 }
 
+/// Search for the processed agent data within the given distance from a point,
+/// ordered from the nearest to the farthest
+#[utoipa::path(
+    path = "/api/processed-agent-data/nearby",
+    params(Circle, Pagination, TimeRange, RoadStates, MagnitudeRange),
+    responses(
+        (
+            status = 200,
+            body = Vec<ProcessedAgentWithId>,
+            description = "List of processed agent data within the given distance, including the distance to each of them"
+        ),
+        (status = 400, description = "Invalid point, radius, pagination or filtering parameters"),
+        (status = "5XX", description = "Internal server error")
+    )
+)]
+#[get("/processed-agent-data/nearby")]
+#[instrument(skip(pool))]
+pub async fn search_processed_agent_data_nearby(
+    circle: Query<Circle>,
+    pagination: Query<Pagination>,
+    filters: Filters,
+    pool: Data<sqlx::PgPool>,
+) -> actix_web::Result<Json<Vec<ProcessedAgentWithId>>> {
+    let filter = filters.into_filter(None);
+    let result = service::search_processed_agent_data_nearby(
+        circle.into_inner(),
+        pagination.page.0,
+        pagination.size.0,
+        &filter,
+        &pool,
+    )
+    .await?;
+    Ok(Json(result))
+}
+
+/// Query parameters of a list of processed agent data, other than [`Filters`]
+#[derive(Debug)]
+struct ListQuery {
+    pagination: Pagination,
+    cursor: PageCursor,
+    sorting: Sorting,
+    format: ListFormat,
+}
+
+/// Query parameters to filter the processed agent data by
+#[derive(Debug)]
+struct Filters {
+    time_range: TimeRange,
+    road_states: RoadStates,
+    magnitude: MagnitudeRange,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct Area {
+    /// The area to search in, formatted as `minLon,minLat,maxLon,maxLat`
+    #[param(value_type = String, example = "30.40,50.35,30.65,50.55")]
+    bbox: BoundingBox,
+}
+
 #[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 struct Pagination {
     /// The page number, starting from 1
     #[serde(default)]
@@ -187,6 +664,75 @@ struct Pagination {
     size: PageSize,
 }
 
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct PageCursor {
+    /// Start the page right after the item at this cursor, as found in the `next` link,
+    /// instead of paging by number. Leave empty to start from the newest item
+    #[serde(default, deserialize_with = "deserialize_page_cursor")]
+    #[param(value_type = Option<String>)]
+    after: Option<Option<Cursor>>,
+}
+
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct Sorting {
+    /// The order of the items: by `timestamp` or `id`, descending if prefixed with `-`
+    #[serde(default)]
+    #[param(inline, default = "-timestamp")]
+    sort: Sort,
+}
+
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct RoadStates {
+    /// Only include data with one of these comma-separated road states
+    #[serde(default, deserialize_with = "deserialize_road_states")]
+    #[param(value_type = Option<String>, example = "SMALL_PITS,LARGE_PITS")]
+    road_state: Vec<RoadState>,
+}
+
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ListFormat {
+    /// Wrap the items into an envelope with the page number, page size and total number of items
+    #[serde(default)]
+    #[param(default = false)]
+    envelope: bool,
+}
+
+/// Unique key of the request, so that its retries within a while only replay the original response
+#[derive(Debug, Default, IntoParams)]
+#[into_params(names("Idempotency-Key"), parameter_in = Header)]
+struct IdempotencyKey(
+    /// Unique key of the request, so that its retries within a while only replay the original
+    /// response. The retries must have the same method, path and body
+    #[param(value_type = Option<String>, max_length = 255, example = "3f2c1e9a-7b4d-4f0e-9c1a-2d5e8b6f0a13")]
+    Option<String>,
+);
+
+/// Request with an `Idempotency-Key`, identified by the hash of its method, path and body
+#[derive(Debug)]
+struct IdempotentRequest {
+    key: String,
+    fingerprint: Vec<u8>,
+}
+
+/// Who claims to make the changes, as recorded in the history of the processed agent data
+#[derive(Debug, Default, IntoParams)]
+#[into_params(names("X-Actor"), parameter_in = Header)]
+struct Actor(
+    /// Who makes the changes, as recorded in their history. The value is not verified:
+    /// any client may claim to be anyone, so it must not be relied upon for accountability
+    #[param(
+        value_type = Option<String>,
+        max_length = 128,
+        pattern = r"^[A-Za-z0-9@._+:-]+$",
+        example = "operator@example.com"
+    )]
+    Option<String>,
+);
+
 #[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Deserialize)]
 #[repr(transparent)]
 #[serde(transparent)]
@@ -199,11 +745,20 @@ struct PageSize(NonZeroU8);
 /// Update a single processed agent data and notify ws subscribers
 #[utoipa::path(
     path = "/api/processed-agent-data/{id}",
-    params(ProcessedAgentId),
+    params(
+        ProcessedAgentId,
+        Actor,
+        (
+            "If-Match" = Option<String>,
+            Header,
+            description = "Only apply the change if the current `ETag` of the data is one of these"
+        ),
+    ),
     request_body(
         content = ProcessedAgent,
         description = "New processed agent data to replace the existing one",
         example = json!({
+            "agent_id": 1,
             "road_state": "NORMAL",
             "accelerometer": {
                 "x": 0.0,
@@ -218,9 +773,20 @@ struct PageSize(NonZeroU8);
         }),
     ),
     responses(
-        (status = 204, description = "Processed agent data updated"),
+        (
+            status = 204,
+            description = "Processed agent data updated",
+            headers(("ETag" = String, description = "New version of the data")),
+        ),
         (status = 400, description = "Invalid ID or request body"),
         (status = 404, description = "Processed agent data for the given ID was not found"),
+        (status = 412, description = "Processed agent data is not of any of the expected versions"),
+        (
+            status = 409,
+            description = "Data has the same timestamp, coordinates and accelerometer values \
+                as another stored one (`duplicate-data` problem)"
+        ),
+        (status = 422, description = "Data violates the domain constraints"),
         (status = "5XX", description = "Internal server error")
     )
 )]
@@ -229,26 +795,126 @@ struct PageSize(NonZeroU8);
 pub async fn update_processed_agent_data(
     id: Path<ProcessedAgentId>,
     data: Json<ProcessedAgent>,
+    if_match: Header<header::IfMatch>,
+    actor: Actor,
+    validation: Data<Validation>,
     subs: Data<ws::Subscribers>,
     pool: Data<sqlx::PgPool>,
 ) -> actix_web::Result<HttpResponse> {
     let id = id.into_inner();
     let data = data.into_inner();
-    let updated = service::update_processed_agent_data(id, data, &pool, &subs).await?;
-    Ok(if updated {
-        HttpResponse::NoContent().finish()
-    } else {
-        HttpResponse::NotFound().finish()
+    data::validate(&data, **validation)?;
+    let if_match = version_match(if_match.into_inner());
+    let updated = service::update_processed_agent_data(
+        id,
+        data,
+        if_match.as_ref(),
+        actor.as_deref(),
+        &pool,
+        &subs,
+    )
+    .await?;
+    Ok(match updated {
+        Conditional::Applied(Some(version)) => HttpResponse::NoContent()
+            .insert_header(header::ETag(entity_tag(version)))
+            .finish(),
+        Conditional::Applied(None) => HttpResponse::NotFound().finish(),
+        Conditional::PreconditionFailed => HttpResponse::PreconditionFailed().finish(),
     })
 }
 
-/// Delete a single processed agent data and notify ws subscribers
+/// Partially update a single processed agent data and notify ws subscribers
 #[utoipa::path(
     path = "/api/processed-agent-data/{id}",
-    params(ProcessedAgentId),
+    params(
+        ProcessedAgentId,
+        Actor,
+        (
+            "If-Match" = Option<String>,
+            Header,
+            description = "Only apply the change if the current `ETag` of the data is one of these"
+        ),
+    ),
+    request_body(
+        content = ProcessedAgentPatch,
+        description = "Fields of the processed agent data to update, \
+            in the form of a JSON Merge Patch (RFC 7396)",
+        content_type = "application/merge-patch+json",
+        example = json!({
+            "road_state": "LARGE_PITS",
+            "gps": {
+                "latitude": 50.45
+            }
+        }),
+    ),
+    responses(
+        (
+            status = 200,
+            body = ProcessedAgent,
+            description = "Processed agent data updated, the resulting data is returned",
+            headers(("ETag" = String, description = "New version of the data")),
+        ),
+        (status = 400, description = "Invalid ID or request body"),
+        (status = 404, description = "Processed agent data for the given ID was not found"),
+        (status = 412, description = "Processed agent data is not of any of the expected versions"),
+        (
+            status = 409,
+            description = "Data has the same timestamp, coordinates and accelerometer values \
+                as another stored one (`duplicate-data` problem)"
+        ),
+        (status = 422, description = "Data violates the domain constraints"),
+        (status = "5XX", description = "Internal server error")
+    )
+)]
+#[patch("/processed-agent-data/{id}")]
+#[instrument(skip(pool, subs))]
+pub async fn patch_processed_agent_data(
+    id: Path<ProcessedAgentId>,
+    patch: Json<ProcessedAgentPatch>,
+    if_match: Header<header::IfMatch>,
+    actor: Actor,
+    validation: Data<Validation>,
+    subs: Data<ws::Subscribers>,
+    pool: Data<sqlx::PgPool>,
+) -> actix_web::Result<HttpResponse> {
+    let id = id.into_inner();
+    let patch = patch.into_inner();
+    data::validate(&patch, **validation)?;
+    let if_match = version_match(if_match.into_inner());
+    let updated = service::patch_processed_agent_data(
+        id,
+        patch,
+        if_match.as_ref(),
+        actor.as_deref(),
+        &pool,
+        &subs,
+    )
+    .await?;
+    Ok(match updated {
+        Conditional::Applied(Some(Versioned { data, version })) => HttpResponse::Ok()
+            .insert_header(header::ETag(entity_tag(version)))
+            .json(data),
+        Conditional::Applied(None) => HttpResponse::NotFound().finish(),
+        Conditional::PreconditionFailed => HttpResponse::PreconditionFailed().finish(),
+    })
+}
+
+/// Move a single processed agent data to the trash and notify ws subscribers
+#[utoipa::path(
+    path = "/api/processed-agent-data/{id}",
+    params(
+        ProcessedAgentId,
+        Actor,
+        (
+            "If-Match" = Option<String>,
+            Header,
+            description = "Only apply the change if the current `ETag` of the data is one of these"
+        ),
+    ),
     responses(
-        (status = 204, description = "Processed agent data deleted or was not present in the first place"),
+        (status = 204, description = "Processed agent data moved to the trash or was not present in the first place"),
         (status = 400, description = "Invalid ID"),
+        (status = 412, description = "Processed agent data is not present or not of any of the expected versions"),
         (status = "5XX", description = "Internal server error")
     )
 )]
@@ -256,12 +922,454 @@ pub async fn update_processed_agent_data(
 #[instrument(skip(pool, subs))]
 pub async fn delete_processed_agent_data(
     id: Path<ProcessedAgentId>,
+    if_match: Header<header::IfMatch>,
+    actor: Actor,
     subs: Data<ws::Subscribers>,
     pool: Data<sqlx::PgPool>,
 ) -> actix_web::Result<HttpResponse> {
     let id = id.into_inner();
-    service::delete_processed_agent_data(id, &pool, &subs).await?;
-    Ok(HttpResponse::NoContent().finish())
+    let if_match = version_match(if_match.into_inner());
+    let deleted =
+        service::delete_processed_agent_data(id, if_match.as_ref(), actor.as_deref(), &pool, &subs)
+            .await?;
+    Ok(match deleted {
+        Conditional::Applied(()) => HttpResponse::NoContent().finish(),
+        Conditional::PreconditionFailed => HttpResponse::PreconditionFailed().finish(),
+    })
+}
+
+/// Move all the processed agent data matching the given criteria to the trash
+/// and notify ws subscribers
+#[utoipa::path(
+    path = "/api/processed-agent-data/delete",
+    params(Actor),
+    request_body(
+        content = ProcessedAgentSelection,
+        description = "Criteria the processed agent data to delete must all match, at least one",
+        examples(
+            ("By IDs" = (
+                value = json!({
+                    "ids": [1, 2, 3]
+                })
+            )),
+            ("By filter" = (
+                value = json!({
+                    "agent_id": 1,
+                    "from": "2023-10-01T00:00:00Z",
+                    "to": "2023-10-01T12:00:00Z",
+                    "road_states": ["LARGE_PITS", "BUMP"]
+                })
+            )),
+        ),
+    ),
+    responses(
+        (status = 200, response = ProcessedAgentDeletion),
+        (status = 400, description = "Invalid or missing criteria"),
+        (status = "5XX", description = "Internal server error")
+    )
+)]
+#[post("/processed-agent-data/delete")]
+#[instrument(skip(pool, subs))]
+pub async fn delete_processed_agent_data_list(
+    selection: Json<ProcessedAgentSelection>,
+    actor: Actor,
+    subs: Data<ws::Subscribers>,
+    pool: Data<sqlx::PgPool>,
+) -> actix_web::Result<Json<ProcessedAgentDeletion>> {
+    let selection = selection.into_inner();
+    let deleted =
+        service::delete_processed_agent_data_list(&selection, actor.as_deref(), &pool, &subs)
+            .await?;
+    Ok(Json(ProcessedAgentDeletion { deleted }))
+}
+
+/// Read a page of the processed agent data in the trash, most recently deleted first
+#[utoipa::path(
+    path = "/api/processed-agent-data/trash",
+    params(Pagination, TimeRange, RoadStates, MagnitudeRange),
+    responses(
+        (
+            status = 200,
+            body = Vec<ProcessedAgentWithId>,
+            description = "List of deleted processed agent data, including when each of them was deleted"
+        ),
+        (status = 400, description = "Invalid pagination or filtering parameters"),
+        (status = "5XX", description = "Internal server error")
+    )
+)]
+#[get("/processed-agent-data/trash")]
+#[instrument(skip(pool))]
+pub async fn read_deleted_processed_agent_data_list(
+    pagination: Query<Pagination>,
+    filters: Filters,
+    pool: Data<sqlx::PgPool>,
+) -> actix_web::Result<Json<Vec<ProcessedAgentWithId>>> {
+    let filter = filters.into_filter(None);
+    let result = service::fetch_deleted_processed_agent_data_list(
+        pagination.page.0,
+        pagination.size.0,
+        &filter,
+        &pool,
+    )
+    .await?;
+    Ok(Json(result))
+}
+
+/// Restore a single processed agent data from the trash and notify ws subscribers
+#[utoipa::path(
+    path = "/api/processed-agent-data/{id}/restore",
+    params(ProcessedAgentId, Actor),
+    responses(
+        (status = 200, body = ProcessedAgent, description = "Processed agent data restored"),
+        (status = 400, description = "Invalid ID"),
+        (status = 404, description = "Processed agent data for the given ID was not found in the trash"),
+        (
+            status = 409,
+            description = "Data has the same timestamp, coordinates and accelerometer values \
+                as another stored one (`duplicate-data` problem)"
+        ),
+        (status = "5XX", description = "Internal server error")
+    )
+)]
+#[post("/processed-agent-data/{id}/restore")]
+#[instrument(skip(pool, subs))]
+pub async fn restore_processed_agent_data(
+    id: Path<ProcessedAgentId>,
+    actor: Actor,
+    subs: Data<ws::Subscribers>,
+    pool: Data<sqlx::PgPool>,
+) -> actix_web::Result<Option<Json<ProcessedAgent>>> {
+    let id = id.into_inner();
+    let restored =
+        service::restore_processed_agent_data(id, actor.as_deref(), &pool, &subs).await?;
+    Ok(restored.map(Json))
+}
+
+/// Read the history of a single processed agent data: its values before each update
+/// or deletion, most recent first
+#[utoipa::path(
+    path = "/api/processed-agent-data/{id}/history",
+    params(ProcessedAgentId),
+    responses(
+        (
+            status = 200,
+            body = Vec<ProcessedAgentRevision>,
+            description = "List of the previous values, empty if the data was never changed. \
+                The actors are recorded from the unverified `X-Actor` headers"
+        ),
+        (status = 400, description = "Invalid ID"),
+        (status = "5XX", description = "Internal server error")
+    )
+)]
+#[get("/processed-agent-data/{id}/history")]
+#[instrument(skip(pool))]
+pub async fn read_processed_agent_data_history(
+    id: Path<ProcessedAgentId>,
+    pool: Data<sqlx::PgPool>,
+) -> actix_web::Result<Json<Vec<ProcessedAgentRevision>>> {
+    let id = id.into_inner();
+    let history = service::fetch_processed_agent_data_history(id, &pool).await?;
+    Ok(Json(history))
+}
+
+/// Read the road-state classification parameters in use
+#[utoipa::path(
+    path = "/api/admin/classification",
+    responses(
+        (status = 200, body = Parameters, description = "Classification parameters in use"),
+        (status = "5XX", description = "Internal server error")
+    )
+)]
+#[get("/admin/classification")]
+#[instrument(skip(classification))]
+pub async fn read_classification_parameters(
+    classification: Data<Classification>,
+) -> Json<Parameters> {
+    Json(Parameters::clone(&classification.current()))
+}
+
+/// Replace the road-state classification parameters, without a restart.
+/// Stored data is not affected until it is reclassified
+#[utoipa::path(
+    path = "/api/admin/classification",
+    params(Actor),
+    request_body(
+        content = Parameters,
+        description = "New classification parameters, persisted across restarts",
+        example = json!({
+            "profile": "car",
+            "window_size": 3,
+            "profiles": {
+                "car": {
+                    "z": { "small_pits": 2.0, "large_pits": 5.0, "bump": 3.0 }
+                },
+                "truck": {
+                    "y": { "small_pits": 4.0, "large_pits": 8.0, "bump": 4.0 },
+                    "z": { "small_pits": 3.0, "large_pits": 7.0, "bump": 4.0 }
+                }
+            }
+        }),
+    ),
+    responses(
+        (status = 200, body = Parameters, description = "Classification parameters replaced"),
+        (status = 400, description = "Invalid request body"),
+        (status = 422, description = "Parameters are inconsistent"),
+        (status = "5XX", description = "Internal server error")
+    )
+)]
+#[put("/admin/classification")]
+#[instrument(skip(classification, pool))]
+pub async fn update_classification_parameters(
+    parameters: Json<Parameters>,
+    actor: Actor,
+    classification: Data<Classification>,
+    validation: Data<Validation>,
+    pool: Data<sqlx::PgPool>,
+) -> actix_web::Result<Json<Parameters>> {
+    let parameters = parameters.into_inner();
+    data::validate(&parameters, **validation)?;
+    classification::update_parameters(&classification, parameters, actor.as_deref(), &pool).await?;
+    Ok(Json(Parameters::clone(&classification.current())))
+}
+
+/// Re-derive the road state of all the stored processed agent data with the parameters in use
+/// and notify ws subscribers about the changed one
+#[utoipa::path(
+    path = "/api/admin/classification/reclassify",
+    params(Actor),
+    responses(
+        (status = 200, body = ProcessedAgentReclassification),
+        (status = "5XX", description = "Internal server error")
+    )
+)]
+#[post("/admin/classification/reclassify")]
+#[instrument(skip(classification, subs, pool))]
+pub async fn reclassify_processed_agent_data(
+    actor: Actor,
+    classification: Data<Classification>,
+    subs: Data<ws::Subscribers>,
+    pool: Data<sqlx::PgPool>,
+) -> actix_web::Result<Json<ProcessedAgentReclassification>> {
+    let reclassified = classification::reclassify_processed_agent_data(
+        &*classification.current(),
+        actor.as_deref(),
+        &subs,
+        &pool,
+    )
+    .await?;
+    Ok(Json(ProcessedAgentReclassification { reclassified }))
+}
+
+fn entity_tag(version: Version) -> EntityTag {
+    EntityTag::new_strong(version.to_string())
+}
+
+/// Versions from an `If-Match` header, compared strongly, or `None` if the header is absent
+fn version_match(if_match: header::IfMatch) -> Option<VersionMatch> {
+    match if_match {
+        header::IfMatch::Any => Some(VersionMatch::Any),
+        header::IfMatch::Items(tags) if tags.is_empty() => None,
+        header::IfMatch::Items(tags) => Some(VersionMatch::Versions(
+            tags.iter()
+                .filter(|tag| !tag.weak)
+                .filter_map(|tag| tag.tag().parse().ok())
+                .collect(),
+        )),
+    }
+}
+
+/// Versions from an `If-None-Match` header, compared weakly, or `None` if the header is absent
+fn version_none_match(if_none_match: header::IfNoneMatch) -> Option<VersionMatch> {
+    match if_none_match {
+        header::IfNoneMatch::Any => Some(VersionMatch::Any),
+        header::IfNoneMatch::Items(tags) if tags.is_empty() => None,
+        header::IfNoneMatch::Items(tags) => Some(VersionMatch::Versions(
+            tags.iter()
+                .filter_map(|tag| tag.tag().parse().ok())
+                .collect(),
+        )),
+    }
+}
+
+impl Actor {
+    const HEADER: HeaderName = HeaderName::from_static("x-actor");
+    const MAX_LENGTH: usize = 128;
+
+    fn is_allowed(c: char) -> bool {
+        c.is_ascii_alphanumeric() || "@._+:-".contains(c)
+    }
+
+    fn as_deref(&self) -> Option<&str> {
+        self.0.as_deref()
+    }
+}
+
+impl FromRequest for Actor {
+    type Error = actix_web::Error;
+    type Future = Ready<actix_web::Result<Self>>;
+
+    fn from_request(req: &HttpRequest, _: &mut dev::Payload) -> Self::Future {
+        ready(optional_header(req, Self::HEADER).and_then(|actor| {
+            match actor {
+                Some(actor) if actor.len() > Self::MAX_LENGTH => {
+                    Err(Problem::new(StatusCode::BAD_REQUEST, "invalid-header")
+                        .with_detail("`X-Actor` header is too long")
+                        .into())
+                }
+                Some(actor) if !actor.chars().all(Self::is_allowed) => {
+                    Err(Problem::new(StatusCode::BAD_REQUEST, "invalid-header")
+                        .with_detail(
+                            "`X-Actor` header may only contain letters, digits and `@._+:-`",
+                        )
+                        .into())
+                }
+                actor => Ok(Actor(actor)),
+            }
+        }))
+    }
+}
+
+impl IdempotencyKey {
+    const HEADER: HeaderName = HeaderName::from_static("idempotency-key");
+    const MAX_LENGTH: usize = 255;
+
+    /// Request with the key, if any, and the posted `data`, either as a single item or as a list
+    fn request<T: Serialize>(
+        self,
+        req: &HttpRequest,
+        data: &[T],
+        single: bool,
+    ) -> AppResult<Option<IdempotentRequest>> {
+        let Some(key) = self.0 else {
+            return Ok(None);
+        };
+        // the body is hashed as parsed, so that its formatting does not matter
+        let body = match data {
+            [item] if single => serde_json::to_vec(item)?,
+            data => serde_json::to_vec(data)?,
+        };
+        let fingerprint = Sha256::new()
+            .chain_update(req.method().as_str())
+            .chain_update(b"\n")
+            .chain_update(req.path())
+            .chain_update(b"\n")
+            .chain_update(body)
+            .finalize()
+            .to_vec();
+
+        Ok(Some(IdempotentRequest { key, fingerprint }))
+    }
+}
+
+impl FromRequest for IdempotencyKey {
+    type Error = actix_web::Error;
+    type Future = Ready<actix_web::Result<Self>>;
+
+    fn from_request(req: &HttpRequest, _: &mut dev::Payload) -> Self::Future {
+        ready(optional_header(req, Self::HEADER).and_then(|key| {
+            match key {
+                Some(key) if key.len() > Self::MAX_LENGTH => {
+                    Err(Problem::new(StatusCode::BAD_REQUEST, "invalid-header")
+                        .with_detail("`Idempotency-Key` header is too long")
+                        .into())
+                }
+                key => Ok(IdempotencyKey(key)),
+            }
+        }))
+    }
+}
+
+/// Trimmed value of the header, or `None` if it is absent or blank
+fn optional_header(req: &HttpRequest, name: HeaderName) -> actix_web::Result<Option<String>> {
+    let Some(value) = req.headers().get(&name) else {
+        return Ok(None);
+    };
+    let value = value.to_str().map_err(|_| {
+        Problem::new(StatusCode::BAD_REQUEST, "invalid-header")
+            .with_detail(format_args!("`{name}` header must be visible ASCII"))
+    })?;
+
+    Ok(Some(value.trim().to_owned()).filter(|value| !value.is_empty()))
+}
+
+impl ListQuery {
+    fn start(&self) -> Page {
+        match self.cursor.after {
+            Some(after) => Page::After(after),
+            None => Page::Number(self.pagination.page.0),
+        }
+    }
+}
+
+impl FromRequest for ListQuery {
+    type Error = actix_web::Error;
+    type Future = Ready<actix_web::Result<Self>>;
+
+    fn from_request(req: &HttpRequest, _: &mut dev::Payload) -> Self::Future {
+        let query = req.query_string();
+        ready((|| {
+            Ok(ListQuery {
+                pagination: Query::<Pagination>::from_query(query)?.into_inner(),
+                cursor: Query::<PageCursor>::from_query(query)?.into_inner(),
+                sorting: Query::<Sorting>::from_query(query)?.into_inner(),
+                format: Query::<ListFormat>::from_query(query)?.into_inner(),
+            })
+        })())
+    }
+}
+
+impl Filters {
+    fn into_filter(self, agent_id: Option<AgentId>) -> ProcessedAgentFilter {
+        ProcessedAgentFilter {
+            agent_id,
+            time_range: self.time_range,
+            road_states: self.road_states.road_state,
+            magnitude: self.magnitude,
+        }
+    }
+}
+
+impl FromRequest for Filters {
+    type Error = actix_web::Error;
+    type Future = Ready<actix_web::Result<Self>>;
+
+    fn from_request(req: &HttpRequest, _: &mut dev::Payload) -> Self::Future {
+        let query = req.query_string();
+        ready((|| {
+            Ok(Filters {
+                time_range: Query::<TimeRange>::from_query(query)?.into_inner(),
+                road_states: Query::<RoadStates>::from_query(query)?.into_inner(),
+                magnitude: Query::<MagnitudeRange>::from_query(query)?.into_inner(),
+            })
+        })())
+    }
+}
+
+fn deserialize_road_states<'de, D>(deserializer: D) -> Result<Vec<RoadState>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = String::deserialize(deserializer)?;
+    value
+        .split(',')
+        .filter(|state| !state.is_empty())
+        .map(|state| RoadState::deserialize(state.trim().into_deserializer()))
+        .collect::<Result<_, serde::de::value::Error>>()
+        .map_err(serde::de::Error::custom)
+}
+
+fn deserialize_page_cursor<'de, D>(deserializer: D) -> Result<Option<Option<Cursor>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = String::deserialize(deserializer)?;
+    if value.is_empty() {
+        return Ok(Some(None));
+    }
+    value
+        .parse()
+        .map(|cursor| Some(Some(cursor)))
+        .map_err(serde::de::Error::custom)
 }
 
 impl Default for PageNumber {
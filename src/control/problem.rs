@@ -0,0 +1,123 @@
+use std::future::Future;
+
+use actix_web::{
+    body::{BoxBody, MessageBody},
+    dev::{Service, ServiceRequest, ServiceResponse},
+    error::{JsonPayloadError, ParseError, PathError, QueryPayloadError},
+    http::{
+        header::{self, HeaderName, HeaderValue},
+        StatusCode,
+    },
+    HttpMessage, HttpResponse, ResponseError,
+};
+use tracing_actix_web::RequestId;
+
+use crate::error::Problem;
+
+pub const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+tokio::task_local! {
+    static CORRELATION_ID: Option<String>;
+}
+
+/// Correlation id of the request being handled, if any
+pub fn correlation_id() -> Option<String> {
+    CORRELATION_ID.try_with(Clone::clone).ok().flatten()
+}
+
+/// Middleware, that assigns a correlation id to every request
+/// and turns the error responses without a JSON body into [`Problem`] details.
+///
+/// The id is taken from the `X-Request-Id` header of the request, if it is valid,
+/// or from the [`RequestId`] of the `TracingLogger`, that has to wrap this middleware.
+/// It is returned in the `X-Request-Id` header of the response.
+pub fn problem_details<S, B>(
+    req: ServiceRequest,
+    service: &S,
+) -> impl Future<Output = actix_web::Result<ServiceResponse<BoxBody>>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody + 'static,
+{
+    let id = req
+        .headers()
+        .get(X_REQUEST_ID)
+        .and_then(|id| id.to_str().ok())
+        .filter(|id| is_valid_id(id))
+        .map(ToOwned::to_owned)
+        .or_else(|| req.extensions().get::<RequestId>().map(ToString::to_string));
+    let response = service.call(req);
+
+    CORRELATION_ID.scope(id.clone(), async move {
+        let mut response = response.await?.map_into_boxed_body();
+        let status = response.status();
+        if (status.is_client_error() || status.is_server_error())
+            && !has_json_body(response.response())
+        {
+            let problem = problem(response.response());
+            response = response.map_body(|head, _| {
+                head.status = problem.status_code();
+                head.headers.insert(
+                    header::CONTENT_TYPE,
+                    HeaderValue::from_static(Problem::CONTENT_TYPE),
+                );
+                BoxBody::new(serde_json::to_string(&problem).unwrap_or_default())
+            });
+        }
+        if let Some(id) = id.and_then(|id| HeaderValue::from_str(&id).ok()) {
+            response.headers_mut().insert(X_REQUEST_ID, id);
+        }
+        Ok(response)
+    })
+}
+
+/// Whether the id is short and safe enough to be echoed back and logged
+fn is_valid_id(id: &str) -> bool {
+    (1..=128).contains(&id.len())
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b':'))
+}
+
+fn has_json_body(response: &HttpResponse) -> bool {
+    response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .and_then(|content_type| content_type.parse::<mime::Mime>().ok())
+        .is_some_and(|mime| mime.subtype() == mime::JSON || mime.suffix() == Some(mime::JSON))
+}
+
+/// Problem details for an error response, made by the handlers or by actix itself
+fn problem(response: &HttpResponse) -> Problem {
+    let error = response.error();
+    let status = match error.and_then(|error| error.as_error::<JsonPayloadError>()) {
+        // actix answers 400 to the bodies of a wrong type
+        Some(JsonPayloadError::ContentType) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        _ => response.status(),
+    };
+    let code = match status {
+        StatusCode::BAD_REQUEST => match error {
+            Some(error) if error.as_error::<JsonPayloadError>().is_some() => "invalid-body",
+            Some(error) if error.as_error::<QueryPayloadError>().is_some() => "invalid-query",
+            Some(error) if error.as_error::<PathError>().is_some() => "invalid-path",
+            Some(error) if error.as_error::<ParseError>().is_some() => "invalid-header",
+            _ => "bad-request",
+        },
+        StatusCode::NOT_FOUND => "not-found",
+        StatusCode::METHOD_NOT_ALLOWED => "method-not-allowed",
+        StatusCode::CONFLICT => "conflict",
+        StatusCode::PRECONDITION_FAILED => "precondition-failed",
+        StatusCode::PAYLOAD_TOO_LARGE => "payload-too-large",
+        StatusCode::UNSUPPORTED_MEDIA_TYPE => "unsupported-media-type",
+        StatusCode::SERVICE_UNAVAILABLE => "service-unavailable",
+        status if status.is_server_error() => "internal-error",
+        _ => "client-error",
+    };
+
+    let problem = Problem::new(status, code);
+    match error {
+        Some(error) if status.is_client_error() => problem.with_detail(error),
+        _ => problem,
+    }
+}
@@ -1,9 +1,11 @@
 use std::{
-    collections::HashMap,
+    collections::{hash_map::RandomState, HashMap, HashSet, VecDeque},
+    hash::BuildHasher,
     sync::{
-        atomic::{self, AtomicU64},
-        Arc,
+        atomic::{self, AtomicBool, AtomicU64},
+        Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard,
     },
+    time::{Duration, SystemTime},
 };
 
 use actix_web::{
@@ -12,22 +14,54 @@ use actix_web::{
     HttpRequest, HttpResponse,
 };
 use actix_ws::{CloseCode, CloseReason};
-use serde::{ser::SerializeStruct, Serialize, Serializer};
+use serde::{ser::SerializeStruct, Deserialize, Serialize, Serializer};
 use tokio::{
-    runtime::Handle,
-    sync::{Mutex, RwLock},
+    sync::Notify,
+    time::{self, Instant},
 };
 use tokio_stream::StreamExt;
 use tracing::instrument;
 
-use crate::{data::Dto, error::AppResult, reclone};
+use crate::{
+    config::WebSocket,
+    data::{AgentId, BoundingBox, Dto, ProcessedAgent, ProcessedAgentId, RoadState},
+    error::AppResult,
+    reclone,
+};
+
+/// How often the message stream is polled, even if nothing has woken its handler up.
+///
+/// When the client closes the connection without closing the session first, the end of
+/// the payload is recorded without waking its reader up (as of actix-http 3.6), so the end
+/// of the stream is only noticed on the next poll. A client, that is gone without closing
+/// the connection, is noticed by the heartbeat instead.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
 
 /// Websocket endpoint for subscribing to processed agent data
+///
+/// A client is notified about all the processed agent data, until it sends a text command:
+/// - `{"type": "subscribe", "filter": {...}}` to only be notified about the data,
+///   matching the [`SubscriptionFilter`];
+/// - `{"type": "unsubscribe"}` to stop being notified.
+///
+/// Each command is answered with a text message of `subscribed`, `unsubscribed`
+/// or `error` kind.
+///
+/// The server pings the client regularly, and closes the session with the `1008` code,
+/// if the client leaves too many pings in a row unanswered or, when the idle timeout
+/// is configured, sends nothing but pongs for too long.
+///
+/// Every notification carries an increasing `seq` number, that restarts along with
+/// the server, and the `epoch` of the server run. A client, that reconnects
+/// with `?since=<seq>&epoch=<epoch>` of the last notification it received, is first sent
+/// the ones it missed, or a `resync` notification, if they are no longer kept
+/// or the server was restarted since, after which it has to reload the data.
 #[get("/ws")]
 #[instrument(skip_all)]
 pub async fn ws_endpoint(
     req: HttpRequest,
     body: web::Payload,
+    resume: web::Query<Resume>,
     subscribers: web::Data<Subscribers>,
 ) -> actix_web::Result<HttpResponse> {
     let (response, session, msg_stream) = actix_ws::handle(&req, body)?;
@@ -35,6 +69,7 @@ pub async fn ws_endpoint(
     actix_web::rt::spawn(ws_handler(
         session,
         msg_stream,
+        resume.into_inner(),
         web::Data::into_inner(subscribers),
     ));
 
@@ -45,19 +80,85 @@ pub async fn ws_endpoint(
 async fn ws_handler(
     session: actix_ws::Session,
     mut msg_stream: actix_ws::MessageStream,
+    resume: Resume,
     subscribers: Arc<Subscribers>,
 ) {
-    let _id = subscribers.add(session.clone()).await;
-    while let Some(msg) = msg_stream.next().await {
+    let settings = subscribers.settings;
+    let id = subscribers.add(session.clone(), resume);
+    let mut poll = time::interval(POLL_INTERVAL);
+    let mut heartbeat = time::interval_at(
+        Instant::now() + settings.heartbeat_interval(),
+        settings.heartbeat_interval(),
+    );
+    let mut last_active = Instant::now();
+    let mut unanswered_pings = 0;
+    loop {
+        let msg = tokio::select! {
+            msg = msg_stream.next() => msg,
+            _ = poll.tick() => continue,
+            _ = heartbeat.tick() => {
+                let idle = settings
+                    .idle_timeout()
+                    .is_some_and(|timeout| last_active.elapsed() >= timeout);
+                let description = if idle {
+                    "Received nothing but pongs for too long"
+                } else if unanswered_pings >= settings.max_missed_pongs() {
+                    "Missed too many pongs"
+                } else {
+                    reclone!(mut session);
+                    if session.ping(b"").await.is_err() {
+                        return; // session closed
+                    }
+                    unanswered_pings += 1;
+                    continue;
+                };
+                tracing::debug!("{description}, closing the session");
+                let reason = Some(CloseReason {
+                    code: CloseCode::Policy,
+                    description: Some(description.into()),
+                });
+                _ = session.close(reason).await;
+                return;
+            }
+        };
+        let Some(msg) = msg else {
+            break; // client disconnected
+        };
+        // the pongs are sent by the browsers on their own, so they tell nothing of the client
+        if !matches!(msg, Ok(actix_ws::Message::Pong(_))) {
+            last_active = Instant::now();
+        }
         reclone!(mut session);
         match msg {
-            Ok(msg) => {
-                if let actix_ws::Message::Ping(bytes) = msg {
-                    if session.pong(&bytes).await.is_err() {
-                        return; // session closed
+            Ok(actix_ws::Message::Pong(_)) => unanswered_pings = 0,
+            Ok(actix_ws::Message::Ping(bytes)) => {
+                if session.pong(&bytes).await.is_err() {
+                    return; // session closed
+                }
+            }
+            Ok(actix_ws::Message::Text(text)) => {
+                let reply = match serde_json::from_str::<Command>(&text) {
+                    Ok(command) => {
+                        let reply = command.reply();
+                        id.subscribers.subscribe(id.value, command.into());
+                        reply
                     }
+                    Err(err) => Reply::Error {
+                        message: err.to_string(),
+                    },
+                };
+                let Ok(reply) = serde_json::to_string(&reply) else {
+                    continue;
+                };
+                if session.text(reply).await.is_err() {
+                    return; // session closed
                 }
             }
+            Ok(actix_ws::Message::Close(reason)) => {
+                _ = session.close(reason).await;
+                return;
+            }
+            Ok(_) => {}
             // <editor-fold desc="Error handling" defaultstate="collapsed">
             Err(err) => match err {
                 actix_ws::ProtocolError::UnmaskedFrame => {
@@ -150,9 +251,9 @@ async fn ws_handler(
                         code: CloseCode::Error,
                         description: Some("I/O error".into()),
                     });
-                    if session.close(reason).await.is_err() {
-                        return; // session closed
-                    }
+                    _ = session.close(reason).await;
+                    // the connection is broken, so no more messages will come
+                    return;
                 }
             },
             // </editor-fold>
@@ -162,30 +263,226 @@ async fn ws_handler(
     _ = session.close(None).await
 }
 
-pub struct Subscribers(RwLock<HashMap<u64, Mutex<actix_ws::Session>>>);
+/// Registry of the WebSocket subscribers.
+///
+/// Its lock is never held across an `.await`, so that the subscribers can be added
+/// and removed synchronously, even when their sessions are dropped.
+pub struct Subscribers {
+    registry: RwLock<HashMap<u64, Subscriber>>,
+    /// Locked after the registry, while a notification is queued for the subscribers,
+    /// to keep them in the order of their sequence numbers
+    log: Mutex<EventLog>,
+    /// Identifies the run of the server, as the sequence numbers restart along with it
+    epoch: String,
+    settings: WebSocket,
+}
+
+/// Query of a client, reconnecting to the WebSocket endpoint
+#[derive(Debug, Deserialize)]
+pub struct Resume {
+    /// Sequence number of the last notification, received before the disconnect
+    since: Option<u64>,
+    /// Epoch of the last notification, received before the disconnect
+    epoch: Option<String>,
+}
+
+/// Recent notifications, sent to the subscribers notified about all the data,
+/// to be replayed to the reconnecting ones
+#[derive(Debug)]
+struct EventLog {
+    events: VecDeque<(u64, Bytes)>,
+    capacity: usize,
+    last_seq: u64,
+}
 
+/// What to do with a message for a subscriber, whose queue is full
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Discard the oldest of the queued messages to make room for the new one
+    #[default]
+    DropOldest,
+    /// Discard the new message
+    DropNewest,
+    /// Close the session of the subscriber
+    Disconnect,
+}
+
+/// Notification about a change of the processed agent data.
+///
+/// The `data` of a deletion and the `previous` data of an update are only used
+/// to pick the subscribers to notify, and are not sent. The subscribers, whose filter
+/// matches either the previous or the updated data, are notified about the update.
 #[derive(Debug)]
 pub enum Message<'a, 'b, T: Dto + ?Sized> {
-    New { id: T::Id<'b>, data: &'a T },
-    Update { id: T::Id<'b>, data: &'a T },
-    Delete { id: T::Id<'b> },
+    New {
+        id: T::Id<'b>,
+        data: &'a T,
+    },
+    Update {
+        id: T::Id<'b>,
+        data: &'a T,
+        previous: &'a T,
+    },
+    Delete {
+        id: T::Id<'b>,
+        data: &'a T,
+    },
+}
+
+/// Criteria of the processed agent data, that a subscriber is notified about.
+///
+/// The data has to match all the given criteria.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SubscriptionFilter {
+    /// Area, formatted as `minLon,minLat,maxLon,maxLat`, the data has to be recorded in
+    #[serde(default)]
+    pub area: Option<BoundingBox>,
+    /// Road states, one of which the data has to have
+    #[serde(default)]
+    pub road_states: Option<HashSet<RoadState>>,
+    /// Agents, one of which the data has to be recorded by
+    #[serde(default)]
+    pub agent_ids: Option<HashSet<AgentId>>,
 }
 
+#[derive(Debug, Default)]
+enum Subscription {
+    /// Notified about all the data, until the first command
+    #[default]
+    All,
+    Filtered(SubscriptionFilter),
+    None,
+}
+
+struct Subscriber {
+    outbox: Arc<Outbox>,
+    subscription: RwLock<Subscription>,
+}
+
+/// Bounded queue of the messages, waiting to be sent to a subscriber by its own task,
+/// so that a slow subscriber holds up nobody else.
+#[derive(Debug)]
+struct Outbox {
+    messages: Mutex<VecDeque<Bytes>>,
+    capacity: usize,
+    overflow: OverflowPolicy,
+    ready: Notify,
+    /// Set when the queue overflows under the [`OverflowPolicy::Disconnect`]
+    overflowed: AtomicBool,
+    /// Set when the subscriber is gone
+    closed: AtomicBool,
+}
+
+/// Command, sent by a subscriber in a text message
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Command {
+    Subscribe {
+        #[serde(default)]
+        filter: SubscriptionFilter,
+    },
+    Unsubscribe,
+}
+
+/// Answer to a [`Command`]
+#[derive(Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum Reply {
+    Subscribed,
+    Unsubscribed,
+    Error { message: String },
+}
+
+/// [`Message`] along with its sequence number
+#[derive(Serialize)]
+struct Sequenced<'m, M> {
+    epoch: &'m str,
+    seq: u64,
+    #[serde(flatten)]
+    message: &'m M,
+}
+
+/// Notification about the part of the changed data, that matches the subscription
+#[derive(Serialize)]
+struct PartialMessage<'a> {
+    kind: &'static str,
+    id: Vec<ProcessedAgentId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<Vec<&'a ProcessedAgent>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data_type: Option<&'static str>,
+}
+
+/// Tells a reconnecting subscriber, that the notifications it missed are no longer kept,
+/// so it has to reload the data. The live notifications follow the `seq`
+#[derive(Serialize)]
+#[serde(tag = "kind", rename = "resync")]
+struct Resync<'a> {
+    epoch: &'a str,
+    seq: u64,
+}
+
+/// Registration of a subscriber, that removes it from the registry when dropped
 struct SubscriberId {
     value: u64,
     subscribers: Arc<Subscribers>,
 }
 
 impl Subscribers {
-    pub fn new() -> Self {
-        Subscribers(RwLock::new(HashMap::new()))
+    pub fn new(settings: WebSocket) -> Self {
+        Subscribers {
+            registry: RwLock::new(HashMap::new()),
+            log: Mutex::new(EventLog::new(settings.event_log_capacity())),
+            epoch: new_epoch(),
+            settings,
+        }
+    }
+
+    /// Number of the connected subscribers
+    pub fn len(&self) -> usize {
+        self.read().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.read().is_empty()
+    }
+
+    /// Identifier of the server run, that the notifications are numbered in
+    pub fn epoch(&self) -> &str {
+        &self.epoch
     }
 
-    async fn add(self: Arc<Self>, session: actix_ws::Session) -> SubscriberId {
-        let mut subscribers = self.0.write().await;
+    /// Register the subscriber, after queueing the notifications it missed since it disconnected
+    fn add(self: Arc<Self>, session: actix_ws::Session, resume: Resume) -> SubscriberId {
+        // no notification is queued in the meantime, so none is missed or sent twice
+        let mut subscribers = self.write();
+
+        let missed = match resume {
+            Resume { since: None, .. } => VecDeque::new(),
+            Resume {
+                since: Some(since),
+                epoch: Some(epoch),
+            } if epoch == self.epoch => self.log().since(since, &self.epoch),
+            // the notifications were numbered by another run of the server
+            Resume { .. } => VecDeque::from([self.log().resync(&self.epoch)]),
+        };
+        let outbox = Arc::new(Outbox::new(
+            self.settings.queue_capacity(),
+            self.settings.overflow(),
+            missed,
+        ));
+        actix_web::rt::spawn(send_queued(session, Arc::clone(&outbox)));
 
         let id = self.next_id();
-        subscribers.insert(id, Mutex::new(session));
+        subscribers.insert(
+            id,
+            Subscriber {
+                outbox,
+                subscription: Default::default(),
+            },
+        );
 
         SubscriberId {
             value: id,
@@ -198,48 +495,320 @@ impl Subscribers {
         NEXT_ID.fetch_add(1, atomic::Ordering::Relaxed)
     }
 
+    fn remove(&self, id: u64) {
+        if let Some(subscriber) = self.write().remove(&id) {
+            subscriber.outbox.close();
+        }
+    }
+
+    fn subscribe(&self, id: u64, subscription: Subscription) {
+        if let Some(subscriber) = self.read().get(&id) {
+            *subscriber
+                .subscription
+                .write()
+                .unwrap_or_else(|e| e.into_inner()) = subscription;
+        }
+    }
+
+    /// Queue the notification about the changed data for each subscriber,
+    /// whose subscription it matches, without waiting for it to be sent.
     pub async fn broadcast<'a, 'b, T>(&self, msg: Message<'a, 'b, T>) -> AppResult<()>
     where
         T: Serialize + Dto + ?Sized,
         <T as Dto>::Id<'b>: Serialize,
     {
-        let data: Bytes = serde_json::to_vec(&msg)?.into();
-
-        let subscribers = self.0.read().await;
-        let mut to_remove = Vec::new();
-        for (&id, subscriber) in subscribers.iter() {
-            let mut subscriber = subscriber.lock().await;
-            let result = subscriber.binary(Bytes::clone(&data)).await;
-            if result.is_err() {
-                to_remove.push(id);
+        let entries = msg.entries();
+
+        let subscribers = self.read();
+        let mut log = self.log();
+        let seq = log.last_seq + 1;
+        let epoch = &self.epoch;
+        let whole: Bytes = serde_json::to_vec(&Sequenced {
+            epoch,
+            seq,
+            message: &msg,
+        })?
+        .into();
+        log.append(seq, Bytes::clone(&whole));
+        for subscriber in subscribers.values() {
+            let data = match &*subscriber
+                .subscription
+                .read()
+                .unwrap_or_else(|e| e.into_inner())
+            {
+                Subscription::All => Bytes::clone(&whole),
+                Subscription::None => continue,
+                Subscription::Filtered(filter) => {
+                    let matching: Vec<_> = entries
+                        .iter()
+                        .filter(|(_, data, previous)| {
+                            filter.matches(data) || previous.is_some_and(|p| filter.matches(p))
+                        })
+                        .collect();
+                    if matching.is_empty() {
+                        continue;
+                    } else if matching.len() == entries.len() {
+                        Bytes::clone(&whole)
+                    } else {
+                        let message = &msg.partial(&matching);
+                        serde_json::to_vec(&Sequenced {
+                            epoch,
+                            seq,
+                            message,
+                        })?
+                        .into()
+                    }
+                }
+            };
+            subscriber.outbox.push(data);
+        }
+
+        Ok(())
+    }
+
+    fn log(&self) -> MutexGuard<'_, EventLog> {
+        self.log.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, HashMap<u64, Subscriber>> {
+        self.registry.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, HashMap<u64, Subscriber>> {
+        self.registry.write().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl EventLog {
+    fn new(capacity: usize) -> Self {
+        Self {
+            events: VecDeque::with_capacity(capacity),
+            capacity,
+            last_seq: 0,
+        }
+    }
+
+    /// Tells to reload the data, after which the notifications follow the last one
+    fn resync(&self, epoch: &str) -> Bytes {
+        let seq = self.last_seq;
+        serde_json::to_vec(&Resync { epoch, seq })
+            .unwrap_or_default()
+            .into()
+    }
+
+    fn append(&mut self, seq: u64, message: Bytes) {
+        if self.events.len() >= self.capacity {
+            self.events.pop_front();
+        }
+        self.events.push_back((seq, message));
+        self.last_seq = seq;
+    }
+
+    /// Notifications of the `epoch` after the `since` one, or the [`Resync`] one,
+    /// if some of them are no longer kept or the `since` one was never sent
+    fn since(&self, since: u64, epoch: &str) -> VecDeque<Bytes> {
+        let first_seq = self
+            .events
+            .front()
+            .map_or(self.last_seq + 1, |&(seq, _)| seq);
+        if since > self.last_seq || since + 1 < first_seq {
+            return VecDeque::from([self.resync(epoch)]);
+        }
+
+        (self.events.iter())
+            .filter(|&&(seq, _)| seq > since)
+            .map(|(_, message)| Bytes::clone(message))
+            .collect()
+    }
+}
+
+impl Outbox {
+    /// Queue with the `missed` messages, that never overflow it
+    fn new(capacity: usize, overflow: OverflowPolicy, missed: VecDeque<Bytes>) -> Self {
+        Self {
+            capacity: capacity + missed.len(),
+            messages: Mutex::new(missed),
+            overflow,
+            ready: Notify::new(),
+            overflowed: AtomicBool::new(false),
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    /// Queue the message, applying the [`OverflowPolicy`] if the queue is full
+    fn push(&self, message: Bytes) {
+        if self.is_done() {
+            return;
+        }
+
+        let mut messages = self.messages.lock().unwrap_or_else(|e| e.into_inner());
+        if messages.len() >= self.capacity {
+            match self.overflow {
+                OverflowPolicy::DropOldest => {
+                    tracing::debug!("Subscriber queue is full, dropping the oldest message");
+                    messages.pop_front();
+                }
+                OverflowPolicy::DropNewest => {
+                    tracing::debug!("Subscriber queue is full, dropping the newest message");
+                    return;
+                }
+                OverflowPolicy::Disconnect => {
+                    tracing::info!("Subscriber queue is full, disconnecting the subscriber");
+                    messages.clear();
+                    self.overflowed.store(true, atomic::Ordering::Release);
+                    drop(messages);
+                    self.ready.notify_one();
+                    return;
+                }
             }
         }
-        drop(subscribers);
+        messages.push_back(message);
+        drop(messages);
+
+        self.ready.notify_one();
+    }
 
-        if !to_remove.is_empty() {
-            let mut subscribers = self.0.write().await;
-            for id in to_remove {
-                subscribers.remove(&id);
+    /// Wait for the next message, unless the subscriber is to be disconnected or is gone
+    async fn pop(&self) -> Option<Bytes> {
+        loop {
+            if self.is_done() {
+                return None;
+            }
+            let message = self
+                .messages
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .pop_front();
+            if message.is_some() {
+                return message;
             }
+            self.ready.notified().await;
         }
+    }
 
-        Ok(())
+    fn close(&self) {
+        self.closed.store(true, atomic::Ordering::Release);
+        self.ready.notify_one();
+    }
+
+    fn is_done(&self) -> bool {
+        self.overflowed.load(atomic::Ordering::Acquire)
+            || self.closed.load(atomic::Ordering::Acquire)
+    }
+}
+
+/// Send the queued messages to the subscriber, until it is gone or falls too far behind
+async fn send_queued(mut session: actix_ws::Session, outbox: Arc<Outbox>) {
+    while let Some(message) = outbox.pop().await {
+        if session.binary(message).await.is_err() {
+            return; // session closed
+        }
+    }
+
+    if outbox.overflowed.load(atomic::Ordering::Acquire) {
+        let reason = Some(CloseReason {
+            code: CloseCode::Policy,
+            description: Some("Too many messages are pending".into()),
+        });
+        _ = session.close(reason).await;
     }
 }
 
-impl Default for Subscribers {
-    #[inline(always)]
-    fn default() -> Self {
-        Self::new()
+impl SubscriptionFilter {
+    pub fn matches(&self, data: &ProcessedAgent) -> bool {
+        let agent = &data.agent_data;
+        self.area.is_none_or(|area| area.contains(&agent.gps))
+            && (self.road_states.as_ref())
+                .is_none_or(|road_states| road_states.contains(&data.road_state))
+            && (self.agent_ids.as_ref()).is_none_or(|agent_ids| agent_ids.contains(&agent.agent_id))
     }
 }
 
+impl From<Command> for Subscription {
+    fn from(command: Command) -> Self {
+        match command {
+            Command::Subscribe { filter } => Subscription::Filtered(filter),
+            Command::Unsubscribe => Subscription::None,
+        }
+    }
+}
+
+impl Command {
+    fn reply(&self) -> Reply {
+        match self {
+            Command::Subscribe { .. } => Reply::Subscribed,
+            Command::Unsubscribe => Reply::Unsubscribed,
+        }
+    }
+}
+
+impl<'a, 'b, T: Dto + ?Sized> Message<'a, 'b, T> {
+    /// Single changed data, along with their ids and their previous values, if updated
+    fn entries(
+        &self,
+    ) -> Vec<(
+        ProcessedAgentId,
+        &'a ProcessedAgent,
+        Option<&'a ProcessedAgent>,
+    )> {
+        match self {
+            Message::New { id, data } | Message::Delete { id, data } => data
+                .entries(id)
+                .into_iter()
+                .map(|(id, data)| (id, data, None))
+                .collect(),
+            Message::Update { id, data, previous } => data
+                .entries(id)
+                .into_iter()
+                .zip(previous.entries(id))
+                .map(|((id, data), (_, previous))| (id, data, Some(previous)))
+                .collect(),
+        }
+    }
+
+    /// Message about the given part of its data, that is always sent as a list
+    fn partial<'e>(
+        &self,
+        entries: &[&(
+            ProcessedAgentId,
+            &'e ProcessedAgent,
+            Option<&ProcessedAgent>,
+        )],
+    ) -> PartialMessage<'e> {
+        let id = entries.iter().map(|(id, ..)| *id).collect();
+        let data = || Some(entries.iter().map(|(_, data, _)| *data).collect());
+        match self {
+            Message::New { .. } => PartialMessage {
+                kind: "new",
+                id,
+                data: data(),
+                data_type: None,
+            },
+            Message::Update { .. } => PartialMessage {
+                kind: "update",
+                id,
+                data: data(),
+                data_type: None,
+            },
+            Message::Delete { .. } => PartialMessage {
+                kind: "delete",
+                id,
+                data: None,
+                data_type: Some(std::any::type_name::<T>()),
+            },
+        }
+    }
+}
+
+/// Unique identifier of the server run
+fn new_epoch() -> String {
+    let hash = RandomState::new().hash_one(SystemTime::now());
+    format!("{hash:016x}")
+}
+
 impl Drop for SubscriberId {
     fn drop(&mut self) {
-        Handle::current().block_on(async move {
-            let mut subscribers = self.subscribers.0.write().await;
-            subscribers.remove(&self.value);
-        });
+        self.subscribers.remove(self.value);
     }
 }
 
@@ -263,14 +832,14 @@ where
                 state.serialize_field("data", data)?;
                 state.end()
             }
-            Message::Update { id, data } => {
+            Message::Update { id, data, .. } => {
                 let mut state = serializer.serialize_struct("Message", 3)?;
                 state.serialize_field(KIND_FIELD, "update")?;
                 state.serialize_field("id", id)?;
                 state.serialize_field("data", data)?;
                 state.end()
             }
-            Message::Delete { id } => {
+            Message::Delete { id, .. } => {
                 let mut state = serializer.serialize_struct("Message", 3)?;
                 state.serialize_field(KIND_FIELD, "delete")?;
                 state.serialize_field("id", id)?;
@@ -1,4 +1,6 @@
 mod model;
 pub mod repo;
+mod validation;
 
 pub use model::*;
+pub use validation::*;
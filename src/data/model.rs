@@ -1,35 +1,140 @@
-use std::fmt::{Debug, Display, Formatter};
+use std::{
+    fmt::{Debug, Display, Formatter},
+    num::NonZeroU32,
+    str::FromStr,
+};
 
 use chrono::{DateTime, Utc};
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
+use sqlx::postgres::{PgHasArrayType, PgTypeInfo};
 use utoipa::{IntoParams, ToResponse, ToSchema};
 
-#[derive(Debug, Deserialize, Serialize, ToResponse, ToSchema)]
+#[derive(Debug, Clone, Deserialize, Serialize, ToResponse, ToSchema)]
 pub struct Accelerometer {
     pub x: f64,
     pub y: f64,
     pub z: f64,
 }
 
-#[derive(Debug, Deserialize, Serialize, ToResponse, ToSchema)]
+#[derive(Debug, Clone, Deserialize, Serialize, ToResponse, ToSchema)]
 pub struct Gps {
     pub latitude: f64,
     pub longitude: f64,
 }
 
-#[derive(Debug, Deserialize, Serialize, ToResponse, ToSchema)]
+#[derive(Debug, Clone, Deserialize, Serialize, ToResponse, ToSchema)]
 pub struct Agent {
+    #[schema(value_type = i32)]
+    pub agent_id: AgentId,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<i32>,
     pub accelerometer: Accelerometer,
     pub gps: Gps,
     pub timestamp: DateTime<Utc>,
 }
 
-#[derive(Debug, Deserialize, Serialize, ToResponse, ToSchema)]
+#[derive(Debug, Clone, Deserialize, Serialize, ToResponse, ToSchema)]
 pub struct ProcessedAgent {
     #[serde(flatten)]
     pub agent_data: Agent,
-    #[schema(max_length = 255, example = "NORMAL")]
-    pub road_state: String,
+    pub road_state: RoadState,
+}
+
+/// Partial processed agent data, of which only the present fields are to be updated.
+#[derive(Debug, Default, Deserialize, ToSchema)]
+pub struct ProcessedAgentPatch {
+    #[serde(default)]
+    #[schema(value_type = Option<i32>)]
+    pub agent_id: Option<AgentId>,
+    /// Set to `null` to detach the data from the user
+    #[serde(default, deserialize_with = "deserialize_present")]
+    #[schema(value_type = Option<i32>)]
+    pub user_id: Option<Option<i32>>,
+    #[serde(default)]
+    pub road_state: Option<RoadState>,
+    #[serde(default)]
+    pub accelerometer: Option<AccelerometerPatch>,
+    #[serde(default)]
+    pub gps: Option<GpsPatch>,
+    #[serde(default)]
+    pub timestamp: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Default, Deserialize, ToSchema)]
+pub struct AccelerometerPatch {
+    #[serde(default)]
+    pub x: Option<f64>,
+    #[serde(default)]
+    pub y: Option<f64>,
+    #[serde(default)]
+    pub z: Option<f64>,
+}
+
+#[derive(Debug, Default, Deserialize, ToSchema)]
+pub struct GpsPatch {
+    #[serde(default)]
+    pub latitude: Option<f64>,
+    #[serde(default)]
+    pub longitude: Option<f64>,
+}
+
+/// State of the road surface, as classified from the agent readings.
+///
+/// Stored as the `road_state` Postgres enum.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Eq,
+    PartialEq,
+    Hash,
+    Deserialize,
+    Serialize,
+    sqlx::Type,
+    ToResponse,
+    ToSchema,
+)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+#[sqlx(type_name = "road_state", rename_all = "SCREAMING_SNAKE_CASE")]
+#[schema(example = "NORMAL")]
+pub enum RoadState {
+    Normal,
+    SmallPits,
+    LargePits,
+    Bump,
+    /// Historic value that could not be mapped onto any of the known states,
+    /// rejected in the incoming data
+    Unknown,
+}
+
+/// What to do with the posted processed agent data, that duplicates the stored data
+/// or the preceding posted items, i.e. has the same timestamp, coordinates and accelerometer values.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicatePolicy {
+    /// Store none of the posted data
+    Reject,
+    /// Store only the data, that is not a duplicate
+    #[default]
+    Ignore,
+    /// Update the stored data with the agent, user and road state of the duplicates
+    Upsert,
+}
+
+/// Change of the processed agent data, recorded in its history.
+///
+/// Stored as the `history_operation` Postgres enum.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Serialize, sqlx::Type, ToSchema)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+#[sqlx(type_name = "history_operation", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum HistoryOperation {
+    Update,
+    /// Moved to the trash
+    Delete,
+    /// Restored from the trash
+    Restore,
+    /// Permanently deleted from the trash
+    Purge,
 }
 
 #[derive(
@@ -53,6 +158,161 @@ pub struct ProcessedAgent {
 /// ID of the processed agent to read, update, or delete.
 pub struct ProcessedAgentId(i32);
 
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Ord,
+    PartialOrd,
+    Eq,
+    PartialEq,
+    Hash,
+    Deserialize,
+    Serialize,
+    sqlx::Type,
+    IntoParams,
+)]
+#[repr(transparent)]
+#[serde(transparent)]
+#[sqlx(transparent)]
+#[into_params(names("agent_id"))]
+/// ID of the agent (vehicle/device) that produced the readings.
+pub struct AgentId(i32);
+
+/// Version of the processed agent data, incremented on every change of it.
+#[derive(Debug, Default, Clone, Copy, Ord, PartialOrd, Eq, PartialEq, Hash, sqlx::Type)]
+#[repr(transparent)]
+#[sqlx(transparent)]
+pub struct Version(i32);
+
+/// Versions of the data expected by a conditional request, as in its `If-Match` header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VersionMatch {
+    /// Any version, as long as the data exists
+    Any,
+    /// One of these versions
+    Versions(Vec<Version>),
+}
+
+/// Data along with its current [`Version`].
+#[derive(Debug)]
+pub struct Versioned<T> {
+    pub data: T,
+    pub version: Version,
+}
+
+#[derive(Debug, Default, Clone, Copy, IntoParams)] // `Deserialize` is derived manually
+#[into_params(parameter_in = Query)]
+pub struct TimeRange {
+    /// Only include data recorded at or after this moment (RFC 3339)
+    #[param(value_type = Option<String>, format = DateTime, example = "2023-10-01T00:00:00Z")]
+    pub from: Option<DateTime<Utc>>,
+    /// Only include data recorded at or before this moment (RFC 3339)
+    #[param(value_type = Option<String>, format = DateTime, example = "2023-10-31T23:59:59Z")]
+    pub to: Option<DateTime<Utc>>,
+}
+
+/// Geographic area, delimited by the minimal and maximal longitude and latitude.
+#[derive(Debug, Clone, Copy, PartialEq)] // `Deserialize` is derived manually
+pub struct BoundingBox {
+    pub min_longitude: f64,
+    pub min_latitude: f64,
+    pub max_longitude: f64,
+    pub max_latitude: f64,
+}
+
+/// Circular geographic area around a point.
+#[derive(Debug, Clone, Copy, PartialEq, IntoParams)] // `Deserialize` is derived manually
+#[into_params(parameter_in = Query)]
+pub struct Circle {
+    /// Latitude of the center, between -90 and 90
+    #[param(minimum = -90, maximum = 90, example = 50.45)]
+    pub latitude: f64,
+    /// Longitude of the center, between -180 and 180
+    #[param(minimum = -180, maximum = 180, example = 30.52)]
+    pub longitude: f64,
+    /// Radius in meters
+    #[param(exclusive_minimum = 0, example = 200)]
+    pub radius: f64,
+}
+
+/// Position of an item in the list of processed agent data, in any of its [`Sort`] orders.
+///
+/// Displayed as an opaque string, to be passed back by the clients as is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)] // `Deserialize` is derived manually
+pub struct Cursor {
+    pub timestamp: DateTime<Utc>,
+    pub id: ProcessedAgentId,
+}
+
+/// Where a page of the processed agent data list starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Page {
+    /// Page number, starting from 1
+    Number(NonZeroU32),
+    /// Right after the item at the cursor, or from the very start of the list
+    After(Option<Cursor>),
+}
+
+/// Range of the accelerometer readings magnitude, i.e. `sqrt(x² + y² + z²)`.
+#[derive(Debug, Default, Clone, Copy, IntoParams)] // `Deserialize` is derived manually
+#[into_params(parameter_in = Query)]
+pub struct MagnitudeRange {
+    /// Only include data with the accelerometer magnitude of at least this value
+    #[param(minimum = 0)]
+    pub min_magnitude: Option<f64>,
+    /// Only include data with the accelerometer magnitude of at most this value
+    #[param(minimum = 0)]
+    pub max_magnitude: Option<f64>,
+}
+
+/// Order of the processed agent data list.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, ToSchema)]
+pub enum Sort {
+    /// From the oldest to the newest
+    #[serde(rename = "timestamp")]
+    TimestampAsc,
+    /// From the newest to the oldest
+    #[default]
+    #[serde(rename = "-timestamp")]
+    TimestampDesc,
+    /// In the order of creation
+    #[serde(rename = "id")]
+    IdAsc,
+    /// In the reverse order of creation
+    #[serde(rename = "-id")]
+    IdDesc,
+}
+
+/// Criteria to select a subset of the processed agent data by.
+#[derive(Debug, Default, Clone)]
+pub struct ProcessedAgentFilter {
+    pub agent_id: Option<AgentId>,
+    pub time_range: TimeRange,
+    /// Road states to include, or all of them if empty
+    pub road_states: Vec<RoadState>,
+    pub magnitude: MagnitudeRange,
+}
+
+/// Processed agent data to delete in bulk, matching all the present criteria.
+///
+/// At least one criterion is required, so that a malformed request can't wipe the whole table.
+#[derive(Debug, Default, Clone, ToSchema)] // `Deserialize` is derived manually
+pub struct ProcessedAgentSelection {
+    /// Only select the data with one of these IDs
+    #[schema(value_type = Option<Vec<i32>>, example = json!([1, 2, 3]))]
+    pub ids: Option<Vec<ProcessedAgentId>>,
+    /// Only select the data produced by this agent
+    #[schema(value_type = Option<i32>)]
+    pub agent_id: Option<AgentId>,
+    /// Only select the data recorded at or after this moment
+    pub from: Option<DateTime<Utc>>,
+    /// Only select the data recorded at or before this moment
+    pub to: Option<DateTime<Utc>>,
+    /// Only select the data with one of these road states
+    pub road_states: Vec<RoadState>,
+}
+
 #[derive(Debug, Serialize, ToResponse, ToSchema)]
 pub struct ProcessedAgentWithId {
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -61,25 +321,161 @@ pub struct ProcessedAgentWithId {
     #[serde(flatten)]
     #[schema(inline)]
     data: ProcessedAgent,
+    /// Great-circle distance to the searched point, in meters
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(minimum = 0, nullable = false)]
+    distance: Option<f64>,
+    /// When the data was moved to the trash, only present in the trash listing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = false)]
+    deleted_at: Option<DateTime<Utc>>,
+}
+
+impl ProcessedAgentWithId {
+    pub fn cursor(&self) -> Option<Cursor> {
+        Some(Cursor {
+            timestamp: self.data.agent_data.timestamp,
+            id: self.id?,
+        })
+    }
+}
+
+/// List of processed agent data, either bare or enveloped with the pagination details.
+#[derive(Debug, Serialize, ToResponse, ToSchema)]
+#[serde(untagged)]
+pub enum ProcessedAgentList {
+    Items(Vec<ProcessedAgentWithId>),
+    Envelope(ProcessedAgentPage),
+}
+
+#[derive(Debug, Serialize, ToResponse, ToSchema)]
+pub struct ProcessedAgentPage {
+    pub items: Vec<ProcessedAgentWithId>,
+    /// The page number, absent when paging by cursor
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(minimum = 1, nullable = false)]
+    pub page: Option<u32>,
+    /// The number of items per page
+    #[schema(minimum = 1, maximum = 20)]
+    pub size: u8,
+    /// The total number of items, matching the filters
+    pub total: u64,
+}
+
+#[derive(Debug, Serialize, ToResponse, ToSchema)]
+pub struct ProcessedAgentDeletion {
+    /// The number of deleted processed agent data
+    pub deleted: u64,
+}
+
+#[derive(Debug, Serialize, ToResponse, ToSchema)]
+pub struct ProcessedAgentReclassification {
+    /// The number of processed agent data, whose road state changed
+    pub reclassified: u64,
+}
+
+/// Where a posted processed agent data has been stored.
+#[derive(Debug)]
+pub struct Stored {
+    pub id: ProcessedAgentId,
+    /// Whether the data was a duplicate of the stored one, found by the `id`
+    pub duplicate: bool,
+    /// Stored data, that the posted one was a duplicate of, as it was before the request
+    pub previous: Option<ProcessedAgent>,
+}
+
+#[derive(Debug, Serialize, ToResponse, ToSchema)]
+pub struct ProcessedAgentCreation {
+    /// IDs of the posted items in the same order, of the stored data for the duplicates.
+    /// Empty, if the posted data was rejected
+    #[schema(value_type = Vec<i32>)]
+    pub ids: Vec<ProcessedAgentId>,
+    /// Positions of the duplicate items in the posted list, starting from 0
+    pub duplicates: Vec<u32>,
+}
+
+/// Response to a creation request with an idempotency key, replayed on the retries of it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdempotentResponse {
+    pub status: u16,
+    pub location: Option<String>,
+    pub ids: Vec<ProcessedAgentId>,
+    pub duplicates: Vec<u32>,
+}
+
+/// Values of the processed agent data, as they were before a change.
+#[derive(Debug, Serialize, ToResponse, ToSchema)]
+pub struct ProcessedAgentRevision {
+    /// Sequence number of the revision, increasing with every change
+    pub revision: i64,
+    pub operation: HistoryOperation,
+    /// When the change was made
+    pub changed_at: DateTime<Utc>,
+    /// Who made the change, as claimed by the unverified `X-Actor` header, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = false)]
+    pub actor: Option<String>,
+    #[serde(flatten)]
+    #[schema(inline)]
+    pub data: ProcessedAgent,
 }
 
 pub trait Dto {
     type Id<'a>;
+
+    /// Single processed agent data, that this one consists of, along with their ids
+    fn entries<'a>(&'a self, id: &Self::Id<'_>) -> Vec<(ProcessedAgentId, &'a ProcessedAgent)>;
 }
 
 impl Dto for ProcessedAgent {
     type Id<'a> = ProcessedAgentId;
+
+    fn entries<'a>(&'a self, id: &Self::Id<'_>) -> Vec<(ProcessedAgentId, &'a ProcessedAgent)> {
+        vec![(*id, self)]
+    }
 }
 
 impl Dto for [ProcessedAgent] {
     type Id<'a> = &'a [ProcessedAgentId];
+
+    fn entries<'a>(&'a self, id: &Self::Id<'_>) -> Vec<(ProcessedAgentId, &'a ProcessedAgent)> {
+        id.iter().copied().zip(self).collect()
+    }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, sqlx::FromRow)]
 pub struct ProcessedAgentDao {
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub(super) id: Option<ProcessedAgentId>,
-    pub(super) road_state: String,
+    pub(super) agent_id: AgentId,
+    pub(super) user_id: Option<i32>,
+    pub(super) road_state: RoadState,
+    pub(super) x: f64,
+    pub(super) y: f64,
+    pub(super) z: f64,
+    pub(super) latitude: f64,
+    pub(super) longitude: f64,
+    pub(super) timestamp: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[sqlx(default)]
+    pub(super) distance: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[sqlx(default)]
+    pub(super) deleted_at: Option<DateTime<Utc>>,
+    #[serde(skip)]
+    #[sqlx(default)]
+    pub(super) version: Option<Version>,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct ProcessedAgentRevisionDao {
+    pub(super) revision: i64,
+    pub(super) operation: HistoryOperation,
+    pub(super) changed_at: DateTime<Utc>,
+    pub(super) actor: Option<String>,
+    pub(super) agent_id: AgentId,
+    pub(super) user_id: Option<i32>,
+    pub(super) road_state: RoadState,
     pub(super) x: f64,
     pub(super) y: f64,
     pub(super) z: f64,
@@ -88,6 +484,12 @@ pub struct ProcessedAgentDao {
     pub(super) timestamp: DateTime<Utc>,
 }
 
+impl PgHasArrayType for RoadState {
+    fn array_type_info() -> PgTypeInfo {
+        PgTypeInfo::with_name("_road_state")
+    }
+}
+
 impl Display for ProcessedAgentId {
     #[inline(always)]
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
@@ -95,10 +497,43 @@ impl Display for ProcessedAgentId {
     }
 }
 
+impl Display for AgentId {
+    #[inline(always)]
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(&self.0, f)
+    }
+}
+
+impl Display for Version {
+    #[inline(always)]
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(&self.0, f)
+    }
+}
+
+impl VersionMatch {
+    pub fn matches(&self, version: Version) -> bool {
+        match self {
+            VersionMatch::Any => true,
+            VersionMatch::Versions(versions) => versions.contains(&version),
+        }
+    }
+}
+
+impl FromStr for Version {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(Version)
+    }
+}
+
 impl From<ProcessedAgentWithId> for ProcessedAgentDao {
     fn from(agent: ProcessedAgentWithId) -> Self {
         Self {
             id: agent.id,
+            agent_id: agent.data.agent_data.agent_id,
+            user_id: agent.data.agent_data.user_id,
             road_state: agent.data.road_state,
             x: agent.data.agent_data.accelerometer.x,
             y: agent.data.agent_data.accelerometer.y,
@@ -106,6 +541,9 @@ impl From<ProcessedAgentWithId> for ProcessedAgentDao {
             latitude: agent.data.agent_data.gps.latitude,
             longitude: agent.data.agent_data.gps.longitude,
             timestamp: agent.data.agent_data.timestamp,
+            distance: agent.distance,
+            deleted_at: agent.deleted_at,
+            version: None,
         }
     }
 }
@@ -114,6 +552,8 @@ impl From<ProcessedAgentDao> for ProcessedAgent {
     fn from(dao: ProcessedAgentDao) -> Self {
         Self {
             agent_data: Agent {
+                agent_id: dao.agent_id,
+                user_id: dao.user_id,
                 accelerometer: Accelerometer {
                     x: dao.x,
                     y: dao.y,
@@ -130,11 +570,358 @@ impl From<ProcessedAgentDao> for ProcessedAgent {
     }
 }
 
+/// Only for the records, selected along with their version
+impl From<ProcessedAgentDao> for Versioned<ProcessedAgent> {
+    fn from(dao: ProcessedAgentDao) -> Self {
+        Self {
+            version: dao.version.unwrap_or_default(),
+            data: dao.into(),
+        }
+    }
+}
+
 impl From<ProcessedAgentDao> for ProcessedAgentWithId {
     fn from(dao: ProcessedAgentDao) -> Self {
         Self {
             id: dao.id,
+            distance: dao.distance,
+            deleted_at: dao.deleted_at,
             data: dao.into(),
         }
     }
 }
+
+impl From<ProcessedAgentRevisionDao> for ProcessedAgentRevision {
+    fn from(dao: ProcessedAgentRevisionDao) -> Self {
+        Self {
+            revision: dao.revision,
+            operation: dao.operation,
+            changed_at: dao.changed_at,
+            actor: dao.actor,
+            data: ProcessedAgent {
+                agent_data: Agent {
+                    agent_id: dao.agent_id,
+                    user_id: dao.user_id,
+                    accelerometer: Accelerometer {
+                        x: dao.x,
+                        y: dao.y,
+                        z: dao.z,
+                    },
+                    gps: Gps {
+                        latitude: dao.latitude,
+                        longitude: dao.longitude,
+                    },
+                    timestamp: dao.timestamp,
+                },
+                road_state: dao.road_state,
+            },
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for TimeRange {
+    fn deserialize<D>(deserializer: D) -> Result<TimeRange, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        struct Unchecked {
+            #[serde(default)]
+            from: Option<DateTime<Utc>>,
+            #[serde(default)]
+            to: Option<DateTime<Utc>>,
+        }
+
+        let Unchecked { from, to } = Unchecked::deserialize(deserializer)?;
+        match (from, to) {
+            (Some(from), Some(to)) if from > to => Err(serde::de::Error::custom(
+                "`from` must not be later than `to`",
+            )),
+            _ => Ok(TimeRange { from, to }),
+        }
+    }
+}
+
+impl Circle {
+    /// Mean radius of the Earth, in meters.
+    pub const EARTH_RADIUS: f64 = 6_371_008.8;
+
+    /// The smallest bounding box, containing the whole circle.
+    pub fn bounding_box(&self) -> BoundingBox {
+        let angular_radius = (self.radius / Self::EARTH_RADIUS).to_degrees();
+
+        let min_latitude = self.latitude - angular_radius;
+        let max_latitude = self.latitude + angular_radius;
+        if min_latitude <= -90.0 || max_latitude >= 90.0 {
+            // the circle covers a pole, so it spans all the longitudes
+            return BoundingBox {
+                min_longitude: -180.0,
+                min_latitude: min_latitude.max(-90.0),
+                max_longitude: 180.0,
+                max_latitude: max_latitude.min(90.0),
+            };
+        }
+
+        let longitude_delta = angular_radius / self.latitude.to_radians().cos();
+        let min_longitude = self.longitude - longitude_delta;
+        let max_longitude = self.longitude + longitude_delta;
+        if min_longitude < -180.0 || max_longitude > 180.0 {
+            // the circle crosses the antimeridian
+            return BoundingBox {
+                min_longitude: -180.0,
+                min_latitude,
+                max_longitude: 180.0,
+                max_latitude,
+            };
+        }
+
+        BoundingBox {
+            min_longitude,
+            min_latitude,
+            max_longitude,
+            max_latitude,
+        }
+    }
+}
+
+impl Display for Cursor {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:016x}{:08x}",
+            self.timestamp.timestamp_micros(),
+            self.id.0
+        )
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        const CURSOR_ERROR: &str = "invalid cursor";
+
+        if s.len() != 24 || !s.is_ascii() {
+            return Err(CURSOR_ERROR);
+        }
+        let (timestamp, id) = s.split_at(16);
+        let timestamp = u64::from_str_radix(timestamp, 16).map_err(|_| CURSOR_ERROR)? as i64;
+        let id = u32::from_str_radix(id, 16).map_err(|_| CURSOR_ERROR)? as i32;
+
+        Ok(Cursor {
+            timestamp: DateTime::from_timestamp(
+                timestamp.div_euclid(1_000_000),
+                timestamp.rem_euclid(1_000_000) as u32 * 1_000,
+            )
+            .ok_or(CURSOR_ERROR)?,
+            id: ProcessedAgentId(id),
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for Cursor {
+    fn deserialize<D>(deserializer: D) -> Result<Cursor, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let value = String::deserialize(deserializer)?;
+        value.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl BoundingBox {
+    pub fn contains(&self, gps: &Gps) -> bool {
+        (self.min_longitude..=self.max_longitude).contains(&gps.longitude)
+            && (self.min_latitude..=self.max_latitude).contains(&gps.latitude)
+    }
+}
+
+impl FromStr for BoundingBox {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        const FORMAT_ERROR: &str =
+            "bounding box must be formatted as `minLon,minLat,maxLon,maxLat`";
+
+        let mut coordinates = s.split(',').map(|c| c.trim().parse::<f64>());
+        let (
+            Some(Ok(min_longitude)),
+            Some(Ok(min_latitude)),
+            Some(Ok(max_longitude)),
+            Some(Ok(max_latitude)),
+            None,
+        ) = (
+            coordinates.next(),
+            coordinates.next(),
+            coordinates.next(),
+            coordinates.next(),
+            coordinates.next(),
+        )
+        else {
+            return Err(FORMAT_ERROR);
+        };
+
+        if ![min_longitude, max_longitude]
+            .iter()
+            .all(|lon| (-180.0..=180.0).contains(lon))
+        {
+            return Err("bounding box longitudes must be between -180 and 180");
+        }
+        if ![min_latitude, max_latitude]
+            .iter()
+            .all(|lat| (-90.0..=90.0).contains(lat))
+        {
+            return Err("bounding box latitudes must be between -90 and 90");
+        }
+        if min_longitude > max_longitude || min_latitude > max_latitude {
+            return Err("bounding box minimal coordinates must not exceed the maximal ones");
+        }
+
+        Ok(BoundingBox {
+            min_longitude,
+            min_latitude,
+            max_longitude,
+            max_latitude,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for MagnitudeRange {
+    fn deserialize<D>(deserializer: D) -> Result<MagnitudeRange, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        struct Unchecked {
+            #[serde(default)]
+            min_magnitude: Option<f64>,
+            #[serde(default)]
+            max_magnitude: Option<f64>,
+        }
+
+        let Unchecked {
+            min_magnitude,
+            max_magnitude,
+        } = Unchecked::deserialize(deserializer)?;
+        if [min_magnitude, max_magnitude]
+            .into_iter()
+            .flatten()
+            .any(|magnitude| !(magnitude.is_finite() && magnitude >= 0.0))
+        {
+            return Err(serde::de::Error::custom(
+                "magnitude must be a non-negative number",
+            ));
+        }
+        match (min_magnitude, max_magnitude) {
+            (Some(min), Some(max)) if min > max => Err(serde::de::Error::custom(
+                "`min_magnitude` must not exceed `max_magnitude`",
+            )),
+            _ => Ok(MagnitudeRange {
+                min_magnitude,
+                max_magnitude,
+            }),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for BoundingBox {
+    fn deserialize<D>(deserializer: D) -> Result<BoundingBox, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let value = String::deserialize(deserializer)?;
+        value.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl<'de> Deserialize<'de> for Circle {
+    fn deserialize<D>(deserializer: D) -> Result<Circle, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        struct Unchecked {
+            latitude: f64,
+            longitude: f64,
+            radius: f64,
+        }
+
+        let Unchecked {
+            latitude,
+            longitude,
+            radius,
+        } = Unchecked::deserialize(deserializer)?;
+        if !(-90.0..=90.0).contains(&latitude) {
+            return Err(serde::de::Error::custom(
+                "latitude must be between -90 and 90",
+            ));
+        }
+        if !(-180.0..=180.0).contains(&longitude) {
+            return Err(serde::de::Error::custom(
+                "longitude must be between -180 and 180",
+            ));
+        }
+        if !(radius.is_finite() && radius > 0.0) {
+            return Err(serde::de::Error::custom("radius must be a positive number"));
+        }
+
+        Ok(Circle {
+            latitude,
+            longitude,
+            radius,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for ProcessedAgentSelection {
+    fn deserialize<D>(deserializer: D) -> Result<ProcessedAgentSelection, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        struct Unchecked {
+            #[serde(default)]
+            ids: Option<Vec<ProcessedAgentId>>,
+            #[serde(default)]
+            agent_id: Option<AgentId>,
+            #[serde(flatten)]
+            time_range: TimeRange,
+            #[serde(default)]
+            road_states: Vec<RoadState>,
+        }
+
+        let Unchecked {
+            ids,
+            agent_id,
+            time_range: TimeRange { from, to },
+            road_states,
+        } = Unchecked::deserialize(deserializer)?;
+        if ids.is_none()
+            && agent_id.is_none()
+            && from.is_none()
+            && to.is_none()
+            && road_states.is_empty()
+        {
+            return Err(serde::de::Error::custom(
+                "at least one of `ids`, `agent_id`, `from`, `to` or `road_states` is required",
+            ));
+        }
+
+        Ok(ProcessedAgentSelection {
+            ids,
+            agent_id,
+            from,
+            to,
+            road_states,
+        })
+    }
+}
+
+/// Distinguishes an explicit `null` (`Some(None)`) from an absent field (`None`, by default).
+fn deserialize_present<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
@@ -1,74 +1,176 @@
-use sqlx::PgPool;
-use std::num::{NonZeroU32, NonZeroU8};
+use chrono::{DateTime, Utc};
+use sqlx::{PgExecutor, PgPool, Postgres, Transaction};
+use std::{
+    num::{NonZeroU32, NonZeroU8},
+    time::Duration,
+};
 
-use super::{ProcessedAgent, ProcessedAgentDao, ProcessedAgentId, ProcessedAgentWithId};
+use super::{
+    AgentId, BoundingBox, Circle, DuplicatePolicy, HistoryOperation, IdempotentResponse, Page,
+    ProcessedAgent, ProcessedAgentDao, ProcessedAgentFilter, ProcessedAgentId, ProcessedAgentPatch,
+    ProcessedAgentRevision, ProcessedAgentRevisionDao, ProcessedAgentSelection,
+    ProcessedAgentWithId, RoadState, Sort, Stored, Version, Versioned,
+};
 
-pub async fn insert_processed_agent_data_list(
-    agents: &[ProcessedAgent],
+/// Unique index on the natural key of the processed agent data, that is not in the trash
+pub const NATURAL_KEY_INDEX: &str = "processed_agent_data_natural_key_idx";
+
+pub async fn begin(
+    actor: Option<&str>,
     pool: &PgPool,
-) -> sqlx::Result<Vec<ProcessedAgentId>> {
+) -> sqlx::Result<Transaction<'static, Postgres>> {
     let mut tx = pool.begin().await?;
-
-    let mut ids = Vec::with_capacity(agents.len());
-    for agent in agents {
-        let record = sqlx::query!(
-            r#"
-            INSERT INTO processed_agent_data (road_state, x, y, z, latitude, longitude, timestamp)
-            VALUES ($1, $2, $3, $4, $5, $6, $7)
-            RETURNING id as "id!: ProcessedAgentId"
-            "#,
-            agent.road_state,
-            agent.agent_data.accelerometer.x,
-            agent.agent_data.accelerometer.y,
-            agent.agent_data.accelerometer.z,
-            agent.agent_data.gps.latitude,
-            agent.agent_data.gps.longitude,
-            agent.agent_data.timestamp
-        )
-        .fetch_one(&mut *tx)
-        .await?;
-        ids.push(record.id);
+    if let Some(actor) = actor {
+        // read by the history trigger, until the end of the transaction
+        sqlx::query!("SELECT set_config('lab2.actor', $1, true)", actor)
+            .fetch_one(&mut *tx)
+            .await?;
     }
 
-    tx.commit().await?;
-
-    Ok(ids)
+    Ok(tx)
 }
 
-pub async fn insert_processed_agent_data(
-    agent: &ProcessedAgent,
-    pool: &PgPool,
-) -> sqlx::Result<ProcessedAgentId> {
-    let record = sqlx::query!(
+pub async fn insert_processed_agent_data_list(
+    agents: &[ProcessedAgent],
+    policy: DuplicatePolicy,
+    executor: impl PgExecutor<'_>,
+) -> sqlx::Result<Vec<Stored>> {
+    let mut agent_ids = Vec::with_capacity(agents.len());
+    let mut user_ids = Vec::with_capacity(agents.len());
+    let mut road_states = Vec::with_capacity(agents.len());
+    let mut xs = Vec::with_capacity(agents.len());
+    let mut ys = Vec::with_capacity(agents.len());
+    let mut zs = Vec::with_capacity(agents.len());
+    let mut latitudes = Vec::with_capacity(agents.len());
+    let mut longitudes = Vec::with_capacity(agents.len());
+    let mut timestamps = Vec::with_capacity(agents.len());
+    for agent in agents {
+        agent_ids.push(agent.agent_data.agent_id);
+        user_ids.push(agent.agent_data.user_id);
+        road_states.push(agent.road_state);
+        xs.push(agent.agent_data.accelerometer.x);
+        ys.push(agent.agent_data.accelerometer.y);
+        zs.push(agent.agent_data.accelerometer.z);
+        latitudes.push(agent.agent_data.gps.latitude);
+        longitudes.push(agent.agent_data.gps.longitude);
+        timestamps.push(agent.agent_data.timestamp);
+    }
+
+    // Only the first of the items with the same natural key is inserted, in the input order, so
+    // that the ids are drawn from the sequence in it. On conflict with the stored data, the no-op
+    // update (unless upserting) makes `RETURNING` include the id of the stored data as well,
+    // which is told apart from the inserted data by `xmax`, set only for the updated rows.
+    // The stored data is also read as it was before the statement, to tell what the update changed.
+    let records = sqlx::query!(
         r#"
-        INSERT INTO processed_agent_data (road_state, x, y, z, latitude, longitude, timestamp)
-        VALUES ($1, $2, $3, $4, $5, $6, $7)
-        RETURNING id as "id!: ProcessedAgentId"
+        WITH input AS (
+            SELECT *, row_number() OVER (
+                PARTITION BY timestamp, latitude, longitude, x, y, z
+                ORDER BY ordinality
+            ) AS occurrence
+            FROM UNNEST(
+                $1::integer[], $2::integer[], $3::road_state[],
+                $4::float8[], $5::float8[], $6::float8[],
+                $7::float8[], $8::float8[], $9::timestamptz[]
+            ) WITH ORDINALITY AS input(agent_id, user_id, road_state, x, y, z, latitude, longitude, timestamp, ordinality)
+        ), existing AS (
+            SELECT id, agent_id, user_id, road_state
+            FROM processed_agent_data
+            WHERE deleted_at IS NULL
+                AND (timestamp, latitude, longitude, x, y, z) IN (
+                    SELECT timestamp, latitude, longitude, x, y, z FROM input
+                )
+        ), stored AS (
+            INSERT INTO processed_agent_data (agent_id, user_id, road_state, x, y, z, latitude, longitude, timestamp)
+            SELECT agent_id, user_id, road_state, x, y, z, latitude, longitude, timestamp
+            FROM input
+            WHERE occurrence = 1
+            ORDER BY ordinality
+            ON CONFLICT (timestamp, latitude, longitude, x, y, z) WHERE deleted_at IS NULL DO UPDATE
+            SET agent_id = CASE WHEN $10 THEN EXCLUDED.agent_id ELSE processed_agent_data.agent_id END,
+                user_id = CASE WHEN $10 THEN EXCLUDED.user_id ELSE processed_agent_data.user_id END,
+                road_state = CASE WHEN $10 THEN EXCLUDED.road_state ELSE processed_agent_data.road_state END
+            RETURNING id, xmax = 0 AS inserted, timestamp, latitude, longitude, x, y, z
+        )
+        SELECT
+            stored.id as "id!: ProcessedAgentId",
+            NOT stored.inserted OR input.occurrence > 1 as "duplicate!",
+            existing.agent_id as "previous_agent_id?: AgentId",
+            existing.user_id as "previous_user_id?",
+            existing.road_state as "previous_road_state?: RoadState",
+            input.x as "x!", input.y as "y!", input.z as "z!",
+            input.latitude as "latitude!", input.longitude as "longitude!",
+            input.timestamp as "timestamp!"
+        FROM input
+        JOIN stored USING (timestamp, latitude, longitude, x, y, z)
+        LEFT JOIN existing ON existing.id = stored.id
+        ORDER BY input.ordinality
         "#,
-        agent.road_state,
-        agent.agent_data.accelerometer.x,
-        agent.agent_data.accelerometer.y,
-        agent.agent_data.accelerometer.z,
-        agent.agent_data.gps.latitude,
-        agent.agent_data.gps.longitude,
-        agent.agent_data.timestamp
+        &agent_ids as &[AgentId],
+        &user_ids as &[Option<i32>],
+        &road_states as &[RoadState],
+        &xs,
+        &ys,
+        &zs,
+        &latitudes,
+        &longitudes,
+        &timestamps,
+        policy == DuplicatePolicy::Upsert
     )
-    .fetch_one(pool)
+    .fetch_all(executor)
     .await?;
 
-    Ok(record.id)
+    Ok(records
+        .into_iter()
+        .map(|record| {
+            let previous = record
+                .previous_agent_id
+                .zip(record.previous_road_state)
+                .map(|(agent_id, road_state)| {
+                    ProcessedAgentDao {
+                        id: None,
+                        agent_id,
+                        user_id: record.previous_user_id,
+                        road_state,
+                        x: record.x,
+                        y: record.y,
+                        z: record.z,
+                        latitude: record.latitude,
+                        longitude: record.longitude,
+                        timestamp: record.timestamp,
+                        distance: None,
+                        deleted_at: None,
+                        version: None,
+                    }
+                    .into()
+                });
+            Stored {
+                id: record.id,
+                duplicate: record.duplicate,
+                previous,
+            }
+        })
+        .collect())
 }
 
 pub async fn select_processed_agent_data(
     id: ProcessedAgentId,
     pool: &PgPool,
-) -> sqlx::Result<Option<ProcessedAgent>> {
+) -> sqlx::Result<Option<Versioned<ProcessedAgent>>> {
     let record = sqlx::query_as!(
         ProcessedAgentDao,
         r#"
-        SELECT NULL as "id?: ProcessedAgentId", road_state, x, y, z, latitude, longitude, timestamp
+        SELECT
+            NULL as "id?: ProcessedAgentId",
+            agent_id as "agent_id: AgentId",
+            user_id,
+            road_state as "road_state: RoadState",
+            x, y, z, latitude, longitude, timestamp,
+            NULL::float8 as "distance?",
+            NULL::timestamptz as "deleted_at?",
+            version as "version?: Version"
         FROM processed_agent_data
-        WHERE id = $1
+        WHERE id = $1 AND deleted_at IS NULL
         "#,
         id as ProcessedAgentId
     )
@@ -79,22 +181,219 @@ pub async fn select_processed_agent_data(
 }
 
 pub async fn select_processed_agent_data_list(
+    page: Page,
+    size: NonZeroU8,
+    sort: Sort,
+    filter: &ProcessedAgentFilter,
+    pool: &PgPool,
+) -> sqlx::Result<Vec<ProcessedAgentWithId>> {
+    let (cursor, offset) = match page {
+        Page::Number(page) => (None, offset(page, size)),
+        Page::After(cursor) => (cursor, 0),
+    };
+
+    // the same statement for every order, but the cursor condition and the order itself,
+    // that have to be literal for the indexes to be used
+    macro_rules! select_page {
+        ($after:literal, $order_by:literal) => {
+            sqlx::query_as!(
+                ProcessedAgentDao,
+                r#"
+                SELECT
+                    id as "id?: ProcessedAgentId",
+                    agent_id as "agent_id: AgentId",
+                    user_id,
+                    road_state as "road_state: RoadState",
+                    x, y, z, latitude, longitude, timestamp,
+                    NULL::float8 as "distance?",
+                    NULL::timestamptz as "deleted_at?",
+                    NULL::integer as "version?: Version"
+                FROM processed_agent_data
+                WHERE deleted_at IS NULL
+                    AND ($1::int4 IS NULL OR agent_id = $1)
+                    AND ($2::timestamptz IS NULL OR timestamp >= $2)
+                    AND ($3::timestamptz IS NULL OR timestamp <= $3)
+                    AND (cardinality($4::road_state[]) = 0 OR road_state = ANY($4))
+                    AND ($5::float8 IS NULL OR sqrt(x * x + y * y + z * z) >= $5)
+                    AND ($6::float8 IS NULL OR sqrt(x * x + y * y + z * z) <= $6)
+                    AND ($7::timestamptz IS NULL OR "#
+                    + $after
+                    + r#")
+                ORDER BY "#
+                    + $order_by
+                    + r#"
+                LIMIT $9 OFFSET $10
+                "#,
+                filter.agent_id as Option<AgentId>,
+                filter.time_range.from,
+                filter.time_range.to,
+                filter.road_states.as_slice() as &[RoadState],
+                filter.magnitude.min_magnitude,
+                filter.magnitude.max_magnitude,
+                cursor.map(|cursor| cursor.timestamp),
+                cursor.map(|cursor| cursor.id) as Option<ProcessedAgentId>,
+                size.get() as i64,
+                offset,
+            )
+            .fetch_all(pool)
+            .await?
+        };
+    }
+    let records = match sort {
+        Sort::TimestampAsc => select_page!("(timestamp, id) > ($7, $8::int4)", "timestamp, id"),
+        Sort::TimestampDesc => select_page!(
+            "(timestamp, id) < ($7, $8::int4)",
+            "timestamp DESC, id DESC"
+        ),
+        Sort::IdAsc => select_page!("id > $8::int4", "id"),
+        Sort::IdDesc => select_page!("id < $8::int4", "id DESC"),
+    };
+
+    Ok(records.into_iter().map(Into::into).collect())
+}
+
+pub async fn count_processed_agent_data(
+    filter: &ProcessedAgentFilter,
+    pool: &PgPool,
+) -> sqlx::Result<u64> {
+    let count = sqlx::query_scalar!(
+        r#"
+        SELECT COUNT(*) as "count!"
+        FROM processed_agent_data
+        WHERE deleted_at IS NULL
+            AND ($1::int4 IS NULL OR agent_id = $1)
+            AND ($2::timestamptz IS NULL OR timestamp >= $2)
+            AND ($3::timestamptz IS NULL OR timestamp <= $3)
+            AND (cardinality($4::road_state[]) = 0 OR road_state = ANY($4))
+            AND ($5::float8 IS NULL OR sqrt(x * x + y * y + z * z) >= $5)
+            AND ($6::float8 IS NULL OR sqrt(x * x + y * y + z * z) <= $6)
+        "#,
+        filter.agent_id as Option<AgentId>,
+        filter.time_range.from,
+        filter.time_range.to,
+        filter.road_states.as_slice() as &[RoadState],
+        filter.magnitude.min_magnitude,
+        filter.magnitude.max_magnitude,
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok(count as u64)
+}
+
+pub async fn select_processed_agent_data_in_area(
+    area: BoundingBox,
     page: NonZeroU32,
     size: NonZeroU8,
+    filter: &ProcessedAgentFilter,
     pool: &PgPool,
 ) -> sqlx::Result<Vec<ProcessedAgentWithId>> {
-    let offset = (page.get() - 1) * size.get() as u32;
+    let offset = offset(page, size);
 
+    // the area condition matches the location index on `point(longitude, latitude)`
     let records = sqlx::query_as!(
         ProcessedAgentDao,
         r#"
-        SELECT id as "id!: ProcessedAgentId", road_state, x, y, z, latitude, longitude, timestamp
+        SELECT
+            id as "id?: ProcessedAgentId",
+            agent_id as "agent_id: AgentId",
+            user_id,
+            road_state as "road_state: RoadState",
+            x, y, z, latitude, longitude, timestamp,
+            NULL::float8 as "distance?",
+            NULL::timestamptz as "deleted_at?",
+            NULL::integer as "version?: Version"
         FROM processed_agent_data
-        ORDER BY timestamp DESC
-        LIMIT $1 OFFSET $2
+        WHERE deleted_at IS NULL
+            AND point(longitude, latitude) <@ box(point($1, $2), point($3, $4))
+            AND ($5::int4 IS NULL OR agent_id = $5)
+            AND ($6::timestamptz IS NULL OR timestamp >= $6)
+            AND ($7::timestamptz IS NULL OR timestamp <= $7)
+            AND (cardinality($8::road_state[]) = 0 OR road_state = ANY($8))
+            AND ($9::float8 IS NULL OR sqrt(x * x + y * y + z * z) >= $9)
+            AND ($10::float8 IS NULL OR sqrt(x * x + y * y + z * z) <= $10)
+        ORDER BY timestamp DESC, id DESC
+        LIMIT $11 OFFSET $12
+        "#,
+        area.min_longitude,
+        area.min_latitude,
+        area.max_longitude,
+        area.max_latitude,
+        filter.agent_id as Option<AgentId>,
+        filter.time_range.from,
+        filter.time_range.to,
+        filter.road_states.as_slice() as &[RoadState],
+        filter.magnitude.min_magnitude,
+        filter.magnitude.max_magnitude,
+        size.get() as i64,
+        offset,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(records.into_iter().map(Into::into).collect())
+}
+
+pub async fn select_processed_agent_data_nearby(
+    circle: Circle,
+    page: NonZeroU32,
+    size: NonZeroU8,
+    filter: &ProcessedAgentFilter,
+    pool: &PgPool,
+) -> sqlx::Result<Vec<ProcessedAgentWithId>> {
+    let offset = offset(page, size);
+    let area = circle.bounding_box();
+
+    // the search is narrowed down with the location index before computing the exact distances;
+    // rounding may take the haversine slightly above 1 for the antipodal points
+    let records = sqlx::query_as!(
+        ProcessedAgentDao,
+        r#"
+        SELECT
+            id as "id?: ProcessedAgentId",
+            agent_id as "agent_id: AgentId",
+            user_id,
+            road_state as "road_state: RoadState",
+            x, y, z, latitude, longitude, timestamp,
+            distance as "distance?",
+            NULL::timestamptz as "deleted_at?",
+            NULL::integer as "version?: Version"
+        FROM (
+            SELECT *, $1::float8 * 2 * asin(least(1.0, sqrt(
+                power(sin(radians(latitude - $2::float8) / 2), 2)
+                + cos(radians($2)) * cos(radians(latitude))
+                * power(sin(radians(longitude - $3::float8) / 2), 2)
+            ))) AS distance
+            FROM processed_agent_data
+            WHERE deleted_at IS NULL
+                AND point(longitude, latitude) <@ box(point($4, $5), point($6, $7))
+                AND ($8::int4 IS NULL OR agent_id = $8)
+                AND ($9::timestamptz IS NULL OR timestamp >= $9)
+                AND ($10::timestamptz IS NULL OR timestamp <= $10)
+                AND (cardinality($11::road_state[]) = 0 OR road_state = ANY($11))
+                AND ($12::float8 IS NULL OR sqrt(x * x + y * y + z * z) >= $12)
+                AND ($13::float8 IS NULL OR sqrt(x * x + y * y + z * z) <= $13)
+        ) AS nearby
+        WHERE distance <= $14
+        ORDER BY distance, id
+        LIMIT $15 OFFSET $16
         "#,
-        size.get() as i32,
-        offset as i32
+        Circle::EARTH_RADIUS,
+        circle.latitude,
+        circle.longitude,
+        area.min_longitude,
+        area.min_latitude,
+        area.max_longitude,
+        area.max_latitude,
+        filter.agent_id as Option<AgentId>,
+        filter.time_range.from,
+        filter.time_range.to,
+        filter.road_states.as_slice() as &[RoadState],
+        filter.magnitude.min_magnitude,
+        filter.magnitude.max_magnitude,
+        circle.radius,
+        size.get() as i64,
+        offset,
     )
     .fetch_all(pool)
     .await?;
@@ -105,15 +404,19 @@ pub async fn select_processed_agent_data_list(
 pub async fn update_processed_agent_data(
     id: ProcessedAgentId,
     data: &ProcessedAgent,
-    pool: &PgPool,
-) -> sqlx::Result<bool> {
-    let result = sqlx::query!(
+    executor: impl PgExecutor<'_>,
+) -> sqlx::Result<Option<Version>> {
+    sqlx::query_scalar!(
         r#"
         UPDATE processed_agent_data
-        SET road_state = $1, x = $2, y = $3, z = $4, latitude = $5, longitude = $6, timestamp = $7
-        WHERE id = $8
+        SET agent_id = $1, user_id = $2, road_state = $3,
+            x = $4, y = $5, z = $6, latitude = $7, longitude = $8, timestamp = $9
+        WHERE id = $10 AND deleted_at IS NULL
+        RETURNING version as "version: Version"
         "#,
-        data.road_state,
+        data.agent_data.agent_id as AgentId,
+        data.agent_data.user_id,
+        data.road_state as RoadState,
         data.agent_data.accelerometer.x,
         data.agent_data.accelerometer.y,
         data.agent_data.accelerometer.z,
@@ -122,25 +425,512 @@ pub async fn update_processed_agent_data(
         data.agent_data.timestamp,
         id as ProcessedAgentId
     )
-    .execute(pool)
+    .fetch_optional(executor)
+    .await
+}
+
+pub async fn patch_processed_agent_data(
+    id: ProcessedAgentId,
+    patch: &ProcessedAgentPatch,
+    executor: impl PgExecutor<'_>,
+) -> sqlx::Result<Option<Versioned<ProcessedAgent>>> {
+    let accelerometer = patch.accelerometer.as_ref();
+    let gps = patch.gps.as_ref();
+
+    let record = sqlx::query_as!(
+        ProcessedAgentDao,
+        r#"
+        UPDATE processed_agent_data
+        SET agent_id = COALESCE($1, agent_id),
+            user_id = CASE WHEN $2 THEN $3 ELSE user_id END,
+            road_state = COALESCE($4, road_state),
+            x = COALESCE($5, x),
+            y = COALESCE($6, y),
+            z = COALESCE($7, z),
+            latitude = COALESCE($8, latitude),
+            longitude = COALESCE($9, longitude),
+            timestamp = COALESCE($10, timestamp)
+        WHERE id = $11 AND deleted_at IS NULL
+        RETURNING
+            NULL as "id?: ProcessedAgentId",
+            agent_id as "agent_id: AgentId",
+            user_id,
+            road_state as "road_state: RoadState",
+            x, y, z, latitude, longitude, timestamp,
+            NULL::float8 as "distance?",
+            NULL::timestamptz as "deleted_at?",
+            version as "version?: Version"
+        "#,
+        patch.agent_id as Option<AgentId>,
+        patch.user_id.is_some(),
+        patch.user_id.flatten(),
+        patch.road_state as Option<RoadState>,
+        accelerometer.and_then(|accelerometer| accelerometer.x),
+        accelerometer.and_then(|accelerometer| accelerometer.y),
+        accelerometer.and_then(|accelerometer| accelerometer.z),
+        gps.and_then(|gps| gps.latitude),
+        gps.and_then(|gps| gps.longitude),
+        patch.timestamp,
+        id as ProcessedAgentId
+    )
+    .fetch_optional(executor)
     .await?;
 
-    Ok(result.rows_affected() != 0)
+    Ok(record.map(Into::into))
+}
+
+pub async fn lock_processed_agent_data(
+    id: ProcessedAgentId,
+    executor: impl PgExecutor<'_>,
+) -> sqlx::Result<Option<ProcessedAgent>> {
+    let record = sqlx::query_as!(
+        ProcessedAgentDao,
+        r#"
+        SELECT
+            NULL as "id?: ProcessedAgentId",
+            agent_id as "agent_id: AgentId",
+            user_id,
+            road_state as "road_state: RoadState",
+            x, y, z, latitude, longitude, timestamp,
+            NULL::float8 as "distance?",
+            NULL::timestamptz as "deleted_at?",
+            NULL::integer as "version?: Version"
+        FROM processed_agent_data
+        WHERE id = $1 AND deleted_at IS NULL
+        FOR UPDATE
+        "#,
+        id as ProcessedAgentId
+    )
+    .fetch_optional(executor)
+    .await?;
+
+    Ok(record.map(Into::into))
+}
+
+pub async fn lock_processed_agent_data_version(
+    id: ProcessedAgentId,
+    executor: impl PgExecutor<'_>,
+) -> sqlx::Result<Option<Version>> {
+    sqlx::query_scalar!(
+        r#"
+        SELECT version as "version: Version"
+        FROM processed_agent_data
+        WHERE id = $1 AND deleted_at IS NULL
+        FOR UPDATE
+        "#,
+        id as ProcessedAgentId
+    )
+    .fetch_optional(executor)
+    .await
 }
 
 pub async fn delete_processed_agent_data(
     id: ProcessedAgentId,
+    executor: impl PgExecutor<'_>,
+) -> sqlx::Result<Option<ProcessedAgent>> {
+    let record = sqlx::query_as!(
+        ProcessedAgentDao,
+        r#"
+        UPDATE processed_agent_data
+        SET deleted_at = now()
+        WHERE id = $1 AND deleted_at IS NULL
+        RETURNING
+            NULL as "id?: ProcessedAgentId",
+            agent_id as "agent_id: AgentId",
+            user_id,
+            road_state as "road_state: RoadState",
+            x, y, z, latitude, longitude, timestamp,
+            NULL::float8 as "distance?",
+            NULL::timestamptz as "deleted_at?",
+            version as "version?: Version"
+        "#,
+        id as ProcessedAgentId
+    )
+    .fetch_optional(executor)
+    .await?;
+
+    Ok(record.map(Into::into))
+}
+
+pub async fn delete_processed_agent_data_list(
+    selection: &ProcessedAgentSelection,
+    executor: impl PgExecutor<'_>,
+) -> sqlx::Result<Vec<(ProcessedAgentId, ProcessedAgent)>> {
+    let records = sqlx::query_as!(
+        ProcessedAgentDao,
+        r#"
+        UPDATE processed_agent_data
+        SET deleted_at = now()
+        WHERE deleted_at IS NULL
+            AND ($1::int4[] IS NULL OR id = ANY($1))
+            AND ($2::int4 IS NULL OR agent_id = $2)
+            AND ($3::timestamptz IS NULL OR timestamp >= $3)
+            AND ($4::timestamptz IS NULL OR timestamp <= $4)
+            AND (cardinality($5::road_state[]) = 0 OR road_state = ANY($5))
+        RETURNING
+            id as "id?: ProcessedAgentId",
+            agent_id as "agent_id: AgentId",
+            user_id,
+            road_state as "road_state: RoadState",
+            x, y, z, latitude, longitude, timestamp,
+            NULL::float8 as "distance?",
+            NULL::timestamptz as "deleted_at?",
+            NULL::integer as "version?: Version"
+        "#,
+        selection.ids.as_deref() as Option<&[ProcessedAgentId]>,
+        selection.agent_id as Option<AgentId>,
+        selection.from,
+        selection.to,
+        selection.road_states.as_slice() as &[RoadState],
+    )
+    .fetch_all(executor)
+    .await?;
+    let mut deleted: Vec<_> = records.into_iter().filter_map(with_id).collect();
+    deleted.sort_unstable_by_key(|&(id, _)| id);
+
+    Ok(deleted)
+}
+
+pub async fn restore_processed_agent_data(
+    id: ProcessedAgentId,
+    executor: impl PgExecutor<'_>,
+) -> sqlx::Result<Option<ProcessedAgent>> {
+    let record = sqlx::query_as!(
+        ProcessedAgentDao,
+        r#"
+        UPDATE processed_agent_data
+        SET deleted_at = NULL
+        WHERE id = $1 AND deleted_at IS NOT NULL
+        RETURNING
+            NULL as "id?: ProcessedAgentId",
+            agent_id as "agent_id: AgentId",
+            user_id,
+            road_state as "road_state: RoadState",
+            x, y, z, latitude, longitude, timestamp,
+            NULL::float8 as "distance?",
+            NULL::timestamptz as "deleted_at?",
+            version as "version?: Version"
+        "#,
+        id as ProcessedAgentId
+    )
+    .fetch_optional(executor)
+    .await?;
+
+    Ok(record.map(Into::into))
+}
+
+pub async fn select_deleted_processed_agent_data_list(
+    page: NonZeroU32,
+    size: NonZeroU8,
+    filter: &ProcessedAgentFilter,
     pool: &PgPool,
-) -> sqlx::Result<bool> {
+) -> sqlx::Result<Vec<ProcessedAgentWithId>> {
+    let offset = offset(page, size);
+
+    let records = sqlx::query_as!(
+        ProcessedAgentDao,
+        r#"
+        SELECT
+            id as "id?: ProcessedAgentId",
+            agent_id as "agent_id: AgentId",
+            user_id,
+            road_state as "road_state: RoadState",
+            x, y, z, latitude, longitude, timestamp,
+            NULL::float8 as "distance?",
+            deleted_at as "deleted_at?",
+            NULL::integer as "version?: Version"
+        FROM processed_agent_data
+        WHERE deleted_at IS NOT NULL
+            AND ($1::int4 IS NULL OR agent_id = $1)
+            AND ($2::timestamptz IS NULL OR timestamp >= $2)
+            AND ($3::timestamptz IS NULL OR timestamp <= $3)
+            AND (cardinality($4::road_state[]) = 0 OR road_state = ANY($4))
+            AND ($5::float8 IS NULL OR sqrt(x * x + y * y + z * z) >= $5)
+            AND ($6::float8 IS NULL OR sqrt(x * x + y * y + z * z) <= $6)
+        ORDER BY deleted_at DESC, id DESC
+        LIMIT $7 OFFSET $8
+        "#,
+        filter.agent_id as Option<AgentId>,
+        filter.time_range.from,
+        filter.time_range.to,
+        filter.road_states.as_slice() as &[RoadState],
+        filter.magnitude.min_magnitude,
+        filter.magnitude.max_magnitude,
+        size.get() as i64,
+        offset,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(records.into_iter().map(Into::into).collect())
+}
+
+pub async fn purge_processed_agent_data(retention: Duration, pool: &PgPool) -> sqlx::Result<u64> {
     let result = sqlx::query!(
         r#"
         DELETE FROM processed_agent_data
+        WHERE deleted_at < now() - make_interval(secs => $1)
+        "#,
+        retention.as_secs_f64()
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+pub async fn select_processed_agent_data_history(
+    id: ProcessedAgentId,
+    pool: &PgPool,
+) -> sqlx::Result<Vec<ProcessedAgentRevision>> {
+    let records = sqlx::query_as!(
+        ProcessedAgentRevisionDao,
+        r#"
+        SELECT
+            revision,
+            operation as "operation: HistoryOperation",
+            changed_at,
+            actor,
+            agent_id as "agent_id: AgentId",
+            user_id,
+            road_state as "road_state: RoadState",
+            x, y, z, latitude, longitude, timestamp
+        FROM processed_agent_data_history
         WHERE id = $1
+        ORDER BY revision DESC
         "#,
         id as ProcessedAgentId
     )
-    .execute(pool)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(records.into_iter().map(Into::into).collect())
+}
+
+pub async fn select_idempotent_response(
+    key: &str,
+    ttl: Duration,
+    executor: impl PgExecutor<'_>,
+) -> sqlx::Result<Option<(Vec<u8>, IdempotentResponse)>> {
+    let record = sqlx::query!(
+        r#"
+        SELECT fingerprint, status, location, ids as "ids: Vec<ProcessedAgentId>", duplicates
+        FROM idempotency_keys
+        WHERE key = $1 AND created_at >= now() - make_interval(secs => $2)
+        "#,
+        key,
+        ttl.as_secs_f64()
+    )
+    .fetch_optional(executor)
+    .await?;
+
+    Ok(record.map(|record| {
+        let response = IdempotentResponse {
+            status: record.status as u16,
+            location: record.location,
+            ids: record.ids,
+            duplicates: record.duplicates.into_iter().map(|i| i as u32).collect(),
+        };
+        (record.fingerprint, response)
+    }))
+}
+
+pub async fn insert_idempotent_response(
+    key: &str,
+    fingerprint: &[u8],
+    ttl: Duration,
+    response: &IdempotentResponse,
+    executor: impl PgExecutor<'_>,
+) -> sqlx::Result<bool> {
+    let duplicates: Vec<i32> = response.duplicates.iter().map(|&i| i as i32).collect();
+
+    // an expired response is replaced, while a live one is kept as is
+    let result = sqlx::query!(
+        r#"
+        INSERT INTO idempotency_keys (key, fingerprint, status, location, ids, duplicates)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        ON CONFLICT (key) DO UPDATE
+        SET created_at = now(),
+            fingerprint = $2,
+            status = $3,
+            location = $4,
+            ids = $5,
+            duplicates = $6
+        WHERE idempotency_keys.created_at < now() - make_interval(secs => $7)
+        "#,
+        key,
+        fingerprint,
+        response.status as i16,
+        response.location,
+        &response.ids as &[ProcessedAgentId],
+        &duplicates,
+        ttl.as_secs_f64()
+    )
+    .execute(executor)
     .await?;
 
     Ok(result.rows_affected() != 0)
 }
+
+pub async fn purge_idempotency_keys(ttl: Duration, pool: &PgPool) -> sqlx::Result<u64> {
+    let result = sqlx::query!(
+        r#"
+        DELETE FROM idempotency_keys
+        WHERE created_at < now() - make_interval(secs => $1)
+        "#,
+        ttl.as_secs_f64()
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+pub async fn select_agent_ids(pool: &PgPool) -> sqlx::Result<Vec<AgentId>> {
+    sqlx::query_scalar!(
+        r#"
+        SELECT DISTINCT agent_id as "agent_id: AgentId"
+        FROM processed_agent_data
+        WHERE deleted_at IS NULL
+        ORDER BY 1
+        "#
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn lock_agent_processed_agent_data(
+    agent_id: AgentId,
+    executor: impl PgExecutor<'_>,
+) -> sqlx::Result<Vec<(ProcessedAgentId, ProcessedAgent)>> {
+    let records = sqlx::query_as!(
+        ProcessedAgentDao,
+        r#"
+        SELECT
+            id as "id?: ProcessedAgentId",
+            agent_id as "agent_id: AgentId",
+            user_id,
+            road_state as "road_state: RoadState",
+            x, y, z, latitude, longitude, timestamp,
+            NULL::float8 as "distance?",
+            NULL::timestamptz as "deleted_at?",
+            NULL::integer as "version?: Version"
+        FROM processed_agent_data
+        WHERE agent_id = $1 AND deleted_at IS NULL
+        ORDER BY timestamp, id
+        FOR UPDATE
+        "#,
+        agent_id as AgentId
+    )
+    .fetch_all(executor)
+    .await?;
+
+    Ok(records.into_iter().filter_map(with_id).collect())
+}
+
+pub async fn select_preceding_processed_agent_data(
+    agent_ids: &[AgentId],
+    before: &[DateTime<Utc>],
+    count: u32,
+    executor: impl PgExecutor<'_>,
+) -> sqlx::Result<Vec<ProcessedAgent>> {
+    let records = sqlx::query_as!(
+        ProcessedAgentDao,
+        r#"
+        SELECT
+            preceding.id as "id?: ProcessedAgentId",
+            preceding.agent_id as "agent_id: AgentId",
+            preceding.user_id,
+            preceding.road_state as "road_state: RoadState",
+            preceding.x, preceding.y, preceding.z,
+            preceding.latitude, preceding.longitude, preceding.timestamp,
+            NULL::float8 as "distance?",
+            NULL::timestamptz as "deleted_at?",
+            NULL::integer as "version?: Version"
+        FROM UNNEST($1::integer[], $2::timestamptz[]) AS input(agent_id, before)
+        CROSS JOIN LATERAL (
+            SELECT *
+            FROM processed_agent_data
+            WHERE agent_id = input.agent_id AND deleted_at IS NULL AND timestamp < input.before
+            ORDER BY timestamp DESC, id DESC
+            LIMIT $3
+        ) AS preceding
+        "#,
+        agent_ids as &[AgentId],
+        before,
+        count as i64
+    )
+    .fetch_all(executor)
+    .await?;
+
+    Ok(records.into_iter().map(Into::into).collect())
+}
+
+pub async fn update_road_states(
+    ids: &[ProcessedAgentId],
+    road_states: &[RoadState],
+    executor: impl PgExecutor<'_>,
+) -> sqlx::Result<Vec<(ProcessedAgentId, ProcessedAgent)>> {
+    let records = sqlx::query_as!(
+        ProcessedAgentDao,
+        r#"
+        UPDATE processed_agent_data
+        SET road_state = input.road_state
+        FROM UNNEST($1::integer[], $2::road_state[]) AS input(id, road_state)
+        WHERE processed_agent_data.id = input.id AND deleted_at IS NULL
+        RETURNING
+            processed_agent_data.id as "id?: ProcessedAgentId",
+            agent_id as "agent_id: AgentId",
+            user_id,
+            processed_agent_data.road_state as "road_state: RoadState",
+            x, y, z, latitude, longitude, timestamp,
+            NULL::float8 as "distance?",
+            NULL::timestamptz as "deleted_at?",
+            version as "version?: Version"
+        "#,
+        ids as &[ProcessedAgentId],
+        road_states as &[RoadState]
+    )
+    .fetch_all(executor)
+    .await?;
+
+    Ok(records.into_iter().filter_map(with_id).collect())
+}
+
+pub async fn select_classification_parameters(pool: &PgPool) -> sqlx::Result<Option<String>> {
+    sqlx::query_scalar!(
+        r#"SELECT parameters::text as "parameters!" FROM classification_parameters"#
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+pub async fn upsert_classification_parameters(
+    parameters: &str,
+    actor: Option<&str>,
+    pool: &PgPool,
+) -> sqlx::Result<()> {
+    sqlx::query!(
+        r#"
+        INSERT INTO classification_parameters (parameters, updated_by)
+        VALUES ($1::text::jsonb, $2)
+        ON CONFLICT (id) DO UPDATE
+        SET parameters = EXCLUDED.parameters, updated_at = now(), updated_by = EXCLUDED.updated_by
+        "#,
+        parameters,
+        actor
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Number of the items before the page, widened so that no page number can overflow it
+fn offset(page: NonZeroU32, size: NonZeroU8) -> i64 {
+    (page.get() as i64 - 1) * size.get() as i64
+}
+
+fn with_id(dao: ProcessedAgentDao) -> Option<(ProcessedAgentId, ProcessedAgent)> {
+    Some((dao.id?, dao.into()))
+}
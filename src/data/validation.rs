@@ -0,0 +1,395 @@
+use std::fmt::Display;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use super::{
+    Accelerometer, AccelerometerPatch, Agent, Gps, GpsPatch, ProcessedAgent, ProcessedAgentPatch,
+    RoadState,
+};
+use crate::config::Validation;
+
+/// Violation of the domain constraints by a field of the incoming data.
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct FieldError {
+    /// JSON pointer to the invalid field, e.g. `/0/gps/latitude`
+    #[schema(example = "/gps/latitude")]
+    pub field: String,
+    #[schema(example = "must be within [-90, 90]")]
+    pub message: String,
+}
+
+/// Incoming data violates the domain constraints.
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+#[error("Invalid data in {} field(s)", .errors.len())]
+pub struct ValidationError {
+    pub errors: Vec<FieldError>,
+}
+
+/// Data, that can be checked against the domain constraints.
+pub trait Validate {
+    fn validate(&self, validator: &mut Validator);
+}
+
+/// Collects the violations of the domain constraints, along with the paths to the invalid fields.
+#[derive(Debug)]
+pub struct Validator {
+    limits: Validation,
+    now: DateTime<Utc>,
+    path: String,
+    errors: Vec<FieldError>,
+}
+
+/// Check the `data` against the domain constraints with the given `limits`.
+pub fn validate<T: Validate + ?Sized>(data: &T, limits: Validation) -> Result<(), ValidationError> {
+    let mut validator = Validator::new(limits);
+    data.validate(&mut validator);
+    validator.finish()
+}
+
+impl Validator {
+    pub fn new(limits: Validation) -> Self {
+        Self {
+            limits,
+            now: Utc::now(),
+            path: String::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    pub fn finish(self) -> Result<(), ValidationError> {
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ValidationError {
+                errors: self.errors,
+            })
+        }
+    }
+
+    /// Validate a nested field, with the `name` appended to the path of the errors.
+    pub fn field(&mut self, name: impl Display, validate: impl FnOnce(&mut Self)) {
+        let len = self.path.len();
+        self.path.push('/');
+        self.path.push_str(&name.to_string());
+        validate(self);
+        self.path.truncate(len);
+    }
+
+    /// Record an error for the current field, unless the `condition` holds.
+    pub fn check(&mut self, condition: bool, message: impl Display) -> bool {
+        if !condition {
+            self.errors.push(FieldError {
+                field: self.path.clone(),
+                message: message.to_string(),
+            });
+        }
+        condition
+    }
+
+    fn finite(&mut self, value: f64) -> bool {
+        self.check(value.is_finite(), "must be a finite number")
+    }
+
+    fn within(&mut self, value: f64, min: f64, max: f64) {
+        if self.finite(value) {
+            self.check(
+                (min..=max).contains(&value),
+                format_args!("must be within [{min}, {max}]"),
+            );
+        }
+    }
+
+    fn acceleration(&mut self, value: f64) {
+        let max = self.limits.max_acceleration();
+        self.within(value, -max, max);
+    }
+
+    fn latitude(&mut self, value: f64) {
+        self.within(value, -90.0, 90.0);
+    }
+
+    fn longitude(&mut self, value: f64) {
+        self.within(value, -180.0, 180.0);
+    }
+
+    fn road_state(&mut self, value: RoadState) {
+        // only the legacy data, that was migrated, may be of an unknown state
+        self.check(value != RoadState::Unknown, "must be a known road state");
+    }
+
+    fn timestamp(&mut self, value: &DateTime<Utc>) {
+        let skew = self.limits.max_clock_skew();
+        let latest = chrono::Duration::from_std(skew)
+            .ok()
+            .and_then(|skew| self.now.checked_add_signed(skew))
+            .unwrap_or(DateTime::<Utc>::MAX_UTC);
+        self.check(
+            *value <= latest,
+            format_args!("must not be more than {}s in the future", skew.as_secs()),
+        );
+    }
+}
+
+impl<T: Validate> Validate for [T] {
+    fn validate(&self, validator: &mut Validator) {
+        for (i, item) in self.iter().enumerate() {
+            validator.field(i, |v| item.validate(v));
+        }
+    }
+}
+
+impl<T: Validate> Validate for Vec<T> {
+    fn validate(&self, validator: &mut Validator) {
+        self.as_slice().validate(validator);
+    }
+}
+
+impl Validate for Accelerometer {
+    fn validate(&self, validator: &mut Validator) {
+        validator.field("x", |v| v.acceleration(self.x));
+        validator.field("y", |v| v.acceleration(self.y));
+        validator.field("z", |v| v.acceleration(self.z));
+    }
+}
+
+impl Validate for Gps {
+    fn validate(&self, validator: &mut Validator) {
+        validator.field("latitude", |v| v.latitude(self.latitude));
+        validator.field("longitude", |v| v.longitude(self.longitude));
+    }
+}
+
+impl Validate for Agent {
+    fn validate(&self, validator: &mut Validator) {
+        validator.field("accelerometer", |v| self.accelerometer.validate(v));
+        validator.field("gps", |v| self.gps.validate(v));
+        validator.field("timestamp", |v| v.timestamp(&self.timestamp));
+    }
+}
+
+impl Validate for ProcessedAgent {
+    fn validate(&self, validator: &mut Validator) {
+        // agent data is flattened, so its fields are at the same level
+        self.agent_data.validate(validator);
+        validator.field("road_state", |v| v.road_state(self.road_state));
+    }
+}
+
+impl Validate for AccelerometerPatch {
+    fn validate(&self, validator: &mut Validator) {
+        for (name, value) in [("x", self.x), ("y", self.y), ("z", self.z)] {
+            if let Some(value) = value {
+                validator.field(name, |v| v.acceleration(value));
+            }
+        }
+    }
+}
+
+impl Validate for GpsPatch {
+    fn validate(&self, validator: &mut Validator) {
+        if let Some(latitude) = self.latitude {
+            validator.field("latitude", |v| v.latitude(latitude));
+        }
+        if let Some(longitude) = self.longitude {
+            validator.field("longitude", |v| v.longitude(longitude));
+        }
+    }
+}
+
+impl Validate for ProcessedAgentPatch {
+    fn validate(&self, validator: &mut Validator) {
+        if let Some(road_state) = self.road_state {
+            validator.field("road_state", |v| v.road_state(road_state));
+        }
+        if let Some(accelerometer) = &self.accelerometer {
+            validator.field("accelerometer", |v| accelerometer.validate(v));
+        }
+        if let Some(gps) = &self.gps {
+            validator.field("gps", |v| gps.validate(v));
+        }
+        if let Some(timestamp) = &self.timestamp {
+            validator.field("timestamp", |v| v.timestamp(timestamp));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+    use serde_json::json;
+
+    use super::*;
+
+    fn limits() -> Validation {
+        serde_json::from_value(json!({
+            "max_clock_skew_secs": 60,
+            "max_acceleration": 160.0,
+        }))
+        .unwrap()
+    }
+
+    fn processed_agent() -> ProcessedAgent {
+        serde_json::from_value(json!({
+            "agent_id": 1,
+            "accelerometer": { "x": 0.1, "y": 0.2, "z": 9.8 },
+            "gps": { "latitude": 50.45, "longitude": 30.52 },
+            "timestamp": Utc::now(),
+            "road_state": "NORMAL",
+        }))
+        .unwrap()
+    }
+
+    fn fields(result: Result<(), ValidationError>) -> Vec<String> {
+        result
+            .unwrap_err()
+            .errors
+            .into_iter()
+            .map(|error| error.field)
+            .collect()
+    }
+
+    #[test]
+    fn valid_data_passes() {
+        assert_eq!(validate(&processed_agent(), limits()), Ok(()));
+    }
+
+    #[test]
+    fn non_finite_numbers_are_rejected() {
+        let mut data = processed_agent();
+        data.agent_data.accelerometer.x = f64::NAN;
+        data.agent_data.accelerometer.z = f64::NEG_INFINITY;
+        data.agent_data.gps.longitude = f64::INFINITY;
+
+        let errors = validate(&data, limits()).unwrap_err().errors;
+        assert_eq!(
+            errors,
+            ["/accelerometer/x", "/accelerometer/z", "/gps/longitude"].map(|field| FieldError {
+                field: field.into(),
+                message: "must be a finite number".into(),
+            })
+        );
+    }
+
+    #[test]
+    fn out_of_range_values_are_rejected() {
+        let mut data = processed_agent();
+        data.agent_data.accelerometer.y = -160.5;
+        data.agent_data.gps.latitude = 90.1;
+        data.agent_data.gps.longitude = -180.1;
+
+        let errors = validate(&data, limits()).unwrap_err().errors;
+        assert_eq!(
+            errors,
+            [
+                ("/accelerometer/y", "must be within [-160, 160]"),
+                ("/gps/latitude", "must be within [-90, 90]"),
+                ("/gps/longitude", "must be within [-180, 180]"),
+            ]
+            .map(|(field, message)| FieldError {
+                field: field.into(),
+                message: message.into(),
+            })
+        );
+    }
+
+    #[test]
+    fn bounds_are_inclusive() {
+        let mut data = processed_agent();
+        data.agent_data.accelerometer.x = 160.0;
+        data.agent_data.gps.latitude = -90.0;
+        data.agent_data.gps.longitude = 180.0;
+
+        assert_eq!(validate(&data, limits()), Ok(()));
+    }
+
+    #[test]
+    fn timestamp_within_clock_skew_passes() {
+        let mut data = processed_agent();
+        data.agent_data.timestamp = Utc::now() + Duration::seconds(30);
+
+        assert_eq!(validate(&data, limits()), Ok(()));
+    }
+
+    #[test]
+    fn future_timestamp_is_rejected() {
+        let mut data = processed_agent();
+        data.agent_data.timestamp = Utc::now() + Duration::seconds(90);
+
+        let errors = validate(&data, limits()).unwrap_err().errors;
+        assert_eq!(
+            errors,
+            [FieldError {
+                field: "/timestamp".into(),
+                message: "must not be more than 60s in the future".into(),
+            }]
+        );
+    }
+
+    #[test]
+    fn unknown_road_state_is_rejected() {
+        let mut data = processed_agent();
+        data.road_state = RoadState::Unknown;
+
+        let errors = validate(&data, limits()).unwrap_err().errors;
+        assert_eq!(
+            errors,
+            [FieldError {
+                field: "/road_state".into(),
+                message: "must be a known road state".into(),
+            }]
+        );
+    }
+
+    #[test]
+    fn list_errors_point_to_the_items() {
+        let mut invalid = processed_agent();
+        invalid.agent_data.gps.latitude = -91.0;
+        let data = vec![processed_agent(), invalid, processed_agent()];
+
+        assert_eq!(fields(validate(&data, limits())), ["/1/gps/latitude"]);
+    }
+
+    #[test]
+    fn first_list_item_is_pointed_to_by_zero() {
+        let mut invalid = processed_agent();
+        invalid.agent_data.gps.latitude = f64::NAN;
+
+        assert_eq!(
+            fields(validate(&[invalid][..], limits())),
+            ["/0/gps/latitude"]
+        );
+    }
+
+    #[test]
+    fn patch_only_checks_present_fields() {
+        let patch: ProcessedAgentPatch = serde_json::from_value(json!({
+            "gps": { "longitude": 30.52 },
+        }))
+        .unwrap();
+
+        assert_eq!(validate(&patch, limits()), Ok(()));
+    }
+
+    #[test]
+    fn patch_errors_point_to_the_fields() {
+        let patch: ProcessedAgentPatch = serde_json::from_value(json!({
+            "road_state": "UNKNOWN",
+            "accelerometer": { "z": 200.0 },
+            "gps": { "latitude": 100.0 },
+            "timestamp": Utc::now() + Duration::hours(1),
+        }))
+        .unwrap();
+
+        assert_eq!(
+            fields(validate(&patch, limits())),
+            [
+                "/road_state",
+                "/accelerometer/z",
+                "/gps/latitude",
+                "/timestamp"
+            ]
+        );
+    }
+}
@@ -1,6 +1,14 @@
-use std::io;
+use std::{fmt::Display, io};
 
-use actix_web::ResponseError;
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use serde::Serialize;
+use sqlx::error::ErrorKind;
+use utoipa::{ToResponse, ToSchema};
+
+use crate::{
+    control::problem,
+    data::{repo, FieldError, ValidationError},
+};
 
 pub type AppResult<T> = Result<T, AppError>;
 
@@ -12,6 +20,140 @@ pub enum AppError {
     Io(#[from] io::Error),
     #[error("Serde error: {0}")]
     Serde(#[from] serde_json::Error),
+    #[error(transparent)]
+    Validation(#[from] ValidationError),
+    #[error("Idempotency key is reused for a different request")]
+    IdempotencyKeyReused,
+}
+
+/// Details of a failed request (RFC 7807), served as `application/problem+json`.
+#[derive(Debug, Clone, PartialEq, Serialize, ToResponse, ToSchema, thiserror::Error)]
+#[error("{status} {code}")]
+#[response(content_type = "application/problem+json")]
+pub struct Problem {
+    /// Always `about:blank`, as the problem is identified by its `code`
+    #[serde(rename = "type")]
+    #[schema(example = "about:blank")]
+    pub kind: String,
+    /// Reason phrase of the status code
+    #[schema(example = "Unprocessable Entity")]
+    pub title: String,
+    #[schema(example = 422)]
+    pub status: u16,
+    /// Stable code of the problem, for clients to tell the problems apart
+    #[schema(example = "validation-failed")]
+    pub code: String,
+    /// Explanation of this occurrence of the problem. Absent for the server errors
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    /// Id of the request, also sent in the `X-Request-Id` header and logged
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+    /// Invalid fields of the `validation-failed` problem
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub errors: Option<Vec<FieldError>>,
+}
+
+impl Problem {
+    pub const CONTENT_TYPE: &'static str = "application/problem+json";
+
+    pub fn new(status: StatusCode, code: &str) -> Self {
+        Self {
+            kind: "about:blank".to_owned(),
+            title: status.canonical_reason().unwrap_or_default().to_owned(),
+            status: status.as_u16(),
+            code: code.to_owned(),
+            detail: None,
+            correlation_id: problem::correlation_id(),
+            errors: None,
+        }
+    }
+
+    pub fn with_detail(mut self, detail: impl Display) -> Self {
+        self.detail = Some(detail.to_string());
+        self
+    }
+}
+
+impl ResponseError for Problem {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code())
+            .content_type(Self::CONTENT_TYPE)
+            .json(self)
+    }
 }
 
-impl ResponseError for AppError {}
+impl AppError {
+    pub fn problem(&self) -> Problem {
+        match self {
+            Self::Sql(sqlx::Error::RowNotFound) => Problem::new(StatusCode::NOT_FOUND, "not-found")
+                .with_detail("Requested data was not found"),
+            Self::Sql(sqlx::Error::Database(error))
+                if error.constraint() == Some(repo::NATURAL_KEY_INDEX) =>
+            {
+                Problem::new(StatusCode::CONFLICT, "duplicate-data").with_detail(
+                    "Data has the same timestamp, coordinates and accelerometer values \
+                    as another stored one",
+                )
+            }
+            Self::Sql(sqlx::Error::Database(error)) if error.kind() != ErrorKind::Other => {
+                Problem::new(StatusCode::CONFLICT, "conflict")
+                    .with_detail("Data conflicts with the stored one")
+            }
+            Self::Sql(
+                sqlx::Error::PoolTimedOut
+                | sqlx::Error::PoolClosed
+                | sqlx::Error::Io(_)
+                | sqlx::Error::Tls(_),
+            ) => Problem::new(StatusCode::SERVICE_UNAVAILABLE, "database-unavailable"),
+            Self::Sql(_) => Problem::new(StatusCode::INTERNAL_SERVER_ERROR, "database-error"),
+            Self::Io(_) => Problem::new(StatusCode::INTERNAL_SERVER_ERROR, "io-error"),
+            Self::Serde(_) => {
+                Problem::new(StatusCode::INTERNAL_SERVER_ERROR, "serialization-error")
+            }
+            Self::Validation(error) => error.problem(),
+            Self::IdempotencyKeyReused => {
+                Problem::new(StatusCode::UNPROCESSABLE_ENTITY, "idempotency-key-reused")
+                    .with_detail("`Idempotency-Key` was already used for a different request")
+            }
+        }
+    }
+}
+
+impl ResponseError for AppError {
+    fn status_code(&self) -> StatusCode {
+        self.problem().status_code()
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let problem = self.problem();
+        if problem.status_code().is_server_error() {
+            // the details are only logged, as they may reveal the internals
+            tracing::error!(error = %self, correlation_id = ?problem.correlation_id, "Request failed");
+        }
+        problem.error_response()
+    }
+}
+
+impl ValidationError {
+    pub fn problem(&self) -> Problem {
+        Problem {
+            errors: Some(self.errors.clone()),
+            ..Problem::new(StatusCode::UNPROCESSABLE_ENTITY, "validation-failed").with_detail(self)
+        }
+    }
+}
+
+impl ResponseError for ValidationError {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::UNPROCESSABLE_ENTITY
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        self.problem().error_response()
+    }
+}
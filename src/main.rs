@@ -8,13 +8,21 @@ use color_eyre::eyre::Result;
 use sqlx::PgPool;
 use tracing_actix_web::TracingLogger;
 use tracing_subscriber::{util::SubscriberInitExt, EnvFilter};
-use utoipa::OpenApi;
+use utoipa::{
+    openapi::{self, Content, Ref, RefOr},
+    Modify, OpenApi,
+};
 use utoipa_swagger_ui::SwaggerUi;
 
 use lab2::{
     config::Configuration,
     control::{self, ws::Subscribers},
-    data, FileStdoutWriter, KtConvenience,
+    data, error,
+    service::{
+        self,
+        classification::{self, Classification},
+    },
+    FileStdoutWriter, KtConvenience,
 };
 
 #[tokio::main]
@@ -39,22 +47,54 @@ async fn main() -> Result<()> {
     sqlx::migrate!("./migrations").run(&pool).await?;
     tracing::info!("Migrations successfully applied");
 
+    tokio::spawn(service::purge_trash(config.trash(), pool.clone()));
+    tokio::spawn(service::purge_idempotency_keys(
+        config.idempotency(),
+        pool.clone(),
+    ));
+    let idempotency = config.idempotency();
+    let deduplication = config.deduplication();
+    let validation = config.validation();
+    let classification =
+        classification::load_parameters(config.classification().clone(), &pool).await?;
+    data::validate(&classification, validation)?;
+    let classification = web::Data::new(Classification::new(classification));
+    // shared by the workers, as any of them may serve the changes for the subscribers of the others
+    let subscribers = web::Data::new(Subscribers::new(config.websocket()));
+
     let openapi = ApiDocs::openapi();
 
     HttpServer::new(move || {
         App::new()
+            .wrap_fn(control::problem::problem_details)
             .wrap(TracingLogger::default())
             .service(
                 web::scope("/api")
                     .wrap(NormalizePath::new(TrailingSlash::Trim))
                     .service(control::ws::ws_endpoint)
                     .service(control::http::create_processed_agent_data)
+                    .service(control::http::create_agent_data)
+                    .service(control::http::search_processed_agent_data)
+                    .service(control::http::search_processed_agent_data_nearby)
+                    .service(control::http::read_deleted_processed_agent_data_list)
                     .service(control::http::read_processed_agent_data)
                     .service(control::http::read_processed_agent_data_list)
+                    .service(control::http::read_agent_processed_agent_data_list)
                     .service(control::http::update_processed_agent_data)
+                    .service(control::http::patch_processed_agent_data)
                     .service(control::http::delete_processed_agent_data)
+                    .service(control::http::delete_processed_agent_data_list)
+                    .service(control::http::restore_processed_agent_data)
+                    .service(control::http::read_processed_agent_data_history)
+                    .service(control::http::read_classification_parameters)
+                    .service(control::http::update_classification_parameters)
+                    .service(control::http::reclassify_processed_agent_data)
                     .app_data(web::Data::new(pool.clone()))
-                    .app_data(web::Data::new(Subscribers::new())),
+                    .app_data(web::Data::new(idempotency))
+                    .app_data(web::Data::new(deduplication))
+                    .app_data(web::Data::new(validation))
+                    .app_data(classification.clone())
+                    .app_data(subscribers.clone()),
             )
             .service(web::redirect("/swagger-ui", "/swagger-ui/"))
             .service(
@@ -73,10 +113,22 @@ async fn main() -> Result<()> {
 #[openapi(
     paths(
         control::http::create_processed_agent_data,
+        control::http::create_agent_data,
         control::http::read_processed_agent_data,
         control::http::read_processed_agent_data_list,
+        control::http::read_agent_processed_agent_data_list,
+        control::http::search_processed_agent_data,
+        control::http::search_processed_agent_data_nearby,
         control::http::update_processed_agent_data,
+        control::http::patch_processed_agent_data,
         control::http::delete_processed_agent_data,
+        control::http::delete_processed_agent_data_list,
+        control::http::read_deleted_processed_agent_data_list,
+        control::http::restore_processed_agent_data,
+        control::http::read_processed_agent_data_history,
+        control::http::read_classification_parameters,
+        control::http::update_classification_parameters,
+        control::http::reclassify_processed_agent_data,
     ),
     components(
         schemas(
@@ -84,15 +136,69 @@ async fn main() -> Result<()> {
             data::Gps,
             data::Agent,
             data::ProcessedAgent,
-            data::ProcessedAgentWithId
+            data::ProcessedAgentWithId,
+            data::ProcessedAgentList,
+            data::ProcessedAgentPage,
+            data::ProcessedAgentPatch,
+            data::AccelerometerPatch,
+            data::GpsPatch,
+            data::ProcessedAgentSelection,
+            data::ProcessedAgentDeletion,
+            data::ProcessedAgentCreation,
+            data::ProcessedAgentReclassification,
+            classification::Parameters,
+            classification::Profile,
+            classification::Thresholds,
+            data::FieldError,
+            error::Problem,
+            data::RoadState,
+            data::HistoryOperation,
+            data::ProcessedAgentRevision,
+            data::Sort
         ),
         responses(
             data::Accelerometer,
             data::Gps,
             data::Agent,
             data::ProcessedAgent,
-            data::ProcessedAgentWithId
+            data::ProcessedAgentWithId,
+            data::ProcessedAgentList,
+            data::ProcessedAgentPage,
+            data::ProcessedAgentDeletion,
+            data::ProcessedAgentCreation,
+            data::ProcessedAgentReclassification,
+            error::Problem,
+            data::RoadState
         ),
-    )
+    ),
+    modifiers(&ProblemResponses)
 )]
 struct ApiDocs;
+
+/// Documents the problem details as the body of the error responses, that have none documented.
+struct ProblemResponses;
+
+impl Modify for ProblemResponses {
+    fn modify(&self, openapi: &mut openapi::OpenApi) {
+        let operations = openapi
+            .paths
+            .paths
+            .values_mut()
+            .flat_map(|item| item.operations.values_mut());
+        for operation in operations {
+            for (status, response) in &mut operation.responses.responses {
+                let RefOr::T(response) = response else {
+                    continue;
+                };
+                if (status.starts_with('4') || status.starts_with('5'))
+                    && response.content.is_empty()
+                {
+                    response.content.insert(
+                        error::Problem::CONTENT_TYPE.to_owned(),
+                        Content::new(Ref::from_schema_name("Problem")),
+                    );
+                }
+            }
+        }
+    }
+}
@@ -1,85 +1,469 @@
-use std::num::{NonZeroU32, NonZeroU8};
+use std::{
+    num::{NonZeroU32, NonZeroU8},
+    time::Duration,
+};
+
+pub mod classification;
 
-use sqlx::PgPool;
+use sqlx::{PgConnection, PgPool};
+use tokio::time::MissedTickBehavior;
 use tracing::instrument;
 
 use crate::{
+    config::{Idempotency, Trash},
     control::ws::{Message, Subscribers},
-    data::{repo, ProcessedAgent, ProcessedAgentId, ProcessedAgentWithId},
-    error::AppResult,
+    data::{
+        repo, Agent, BoundingBox, Circle, DuplicatePolicy, IdempotentResponse, Page,
+        ProcessedAgent, ProcessedAgentFilter, ProcessedAgentId, ProcessedAgentPatch,
+        ProcessedAgentRevision, ProcessedAgentSelection, ProcessedAgentWithId, Sort, Stored,
+        Version, VersionMatch, Versioned,
+    },
+    error::{AppError, AppResult},
+    service::classification::Classifier,
 };
 
-#[instrument(skip(subs, pool))]
-pub async fn create_processed_agent_data(
-    data: ProcessedAgent,
+/// Outcome of a change, conditioned on the current version of the data.
+#[derive(Debug)]
+pub enum Conditional<T> {
+    Applied(T),
+    /// The data does not exist or its current version was not expected
+    PreconditionFailed,
+}
+
+/// Outcome of the creation of processed agent data under a [`DuplicatePolicy`].
+#[derive(Debug)]
+pub enum Creation {
+    /// Where each of the posted items has been stored, in the same order
+    Stored(Vec<Stored>),
+    /// Nothing was stored, because of the duplicates at these positions
+    Rejected(Vec<u32>),
+}
+
+#[instrument(skip(data, subs, pool))]
+pub async fn create_processed_agent_data_list(
+    data: Vec<ProcessedAgent>,
+    policy: DuplicatePolicy,
     subs: &Subscribers,
     pool: &PgPool,
-) -> AppResult<ProcessedAgentId> {
-    let id = repo::insert_processed_agent_data(&data, pool).await?;
-    subs.broadcast(Message::New { id, data: &data }).await?;
+) -> AppResult<Creation> {
+    let mut tx = pool.begin().await?;
+    let creation = store_processed_agent_data_list(&data, policy, &mut tx).await?;
+    if let Creation::Stored(stored) = &creation {
+        tx.commit().await?;
+        notify_stored(stored, data, policy, subs).await?;
+    }
 
-    Ok(id)
+    Ok(creation)
 }
 
-#[instrument(skip(subs, pool))]
-pub async fn create_processed_agent_data_list(
+/// Attach the road state, derived by the `classifier`, to the agent data,
+/// then store it and notify about it, as [`create_processed_agent_data_list`] does.
+#[instrument(skip(data, classifier, subs, pool))]
+pub async fn create_agent_data_list(
+    data: Vec<Agent>,
+    classifier: &dyn Classifier,
+    policy: DuplicatePolicy,
+    subs: &Subscribers,
+    pool: &PgPool,
+) -> AppResult<Creation> {
+    let data = classification::classify(data, classifier, pool).await?;
+    create_processed_agent_data_list(data, policy, subs, pool).await
+}
+
+/// Same as [`create_agent_data_list`], but idempotent,
+/// as [`create_processed_agent_data_list_idempotently`] is.
+#[instrument(skip(fingerprint, data, classifier, respond, subs, pool))]
+#[allow(clippy::too_many_arguments)]
+pub async fn create_agent_data_list_idempotently(
+    key: &str,
+    fingerprint: &[u8],
+    ttl: Duration,
+    data: Vec<Agent>,
+    classifier: &dyn Classifier,
+    policy: DuplicatePolicy,
+    respond: impl FnOnce(&Creation) -> AppResult<IdempotentResponse>,
+    subs: &Subscribers,
+    pool: &PgPool,
+) -> AppResult<IdempotentResponse> {
+    let data = classification::classify(data, classifier, pool).await?;
+    create_processed_agent_data_list_idempotently(
+        key,
+        fingerprint,
+        ttl,
+        data,
+        policy,
+        respond,
+        subs,
+        pool,
+    )
+    .await
+}
+
+/// Same as [`create_processed_agent_data_list`], unless a request with the same idempotency `key`
+/// was handled within the `ttl`, in which case its response is replayed instead.
+/// The `fingerprint` of the request must match the one of the handled request,
+/// otherwise the key is reused for a different request, which is an error.
+///
+/// The response to store is made by `respond` from the outcome of the creation.
+/// Rejected data is not stored, so neither is the response to it.
+#[instrument(skip(fingerprint, data, respond, subs, pool))]
+#[allow(clippy::too_many_arguments)]
+pub async fn create_processed_agent_data_list_idempotently(
+    key: &str,
+    fingerprint: &[u8],
+    ttl: Duration,
     data: Vec<ProcessedAgent>,
+    policy: DuplicatePolicy,
+    respond: impl FnOnce(&Creation) -> AppResult<IdempotentResponse>,
     subs: &Subscribers,
     pool: &PgPool,
-) -> AppResult<Vec<ProcessedAgentId>> {
-    let ids = repo::insert_processed_agent_data_list(&data, pool).await?;
-    subs.broadcast(Message::New {
-        id: ids.as_slice(),
-        data: data.as_slice(),
-    })
-    .await?;
+) -> AppResult<IdempotentResponse> {
+    if let Some(response) = replay(key, fingerprint, ttl, pool).await? {
+        return Ok(response);
+    }
+
+    let mut tx = pool.begin().await?;
+    let creation = store_processed_agent_data_list(&data, policy, &mut tx).await?;
+    let response = respond(&creation)?;
+    let Creation::Stored(stored) = creation else {
+        return Ok(response);
+    };
+    if !repo::insert_idempotent_response(key, fingerprint, ttl, &response, &mut *tx).await? {
+        // a concurrent request with the same key has been handled first
+        tx.rollback().await?;
+        let response = replay(key, fingerprint, ttl, pool)
+            .await?
+            .ok_or(sqlx::Error::RowNotFound)?;
+        return Ok(response);
+    }
+    tx.commit().await?;
+    notify_stored(&stored, data, policy, subs).await?;
 
-    Ok(ids)
+    Ok(response)
+}
+
+/// Response to the request with the idempotency `key` handled within the `ttl`, if any
+async fn replay(
+    key: &str,
+    fingerprint: &[u8],
+    ttl: Duration,
+    pool: &PgPool,
+) -> AppResult<Option<IdempotentResponse>> {
+    match repo::select_idempotent_response(key, ttl, pool).await? {
+        Some((stored, _)) if stored != fingerprint => Err(AppError::IdempotencyKeyReused),
+        replayed => Ok(replayed.map(|(_, response)| response)),
+    }
 }
 
 #[instrument(skip(pool))]
 pub async fn fetch_processed_agent_data(
     id: ProcessedAgentId,
     pool: &PgPool,
-) -> AppResult<Option<ProcessedAgent>> {
+) -> AppResult<Option<Versioned<ProcessedAgent>>> {
     Ok(repo::select_processed_agent_data(id, pool).await?)
 }
 
 #[instrument(skip(pool))]
 pub async fn fetch_processed_agent_data_list(
+    page: Page,
+    size: NonZeroU8,
+    sort: Sort,
+    filter: &ProcessedAgentFilter,
+    pool: &PgPool,
+) -> AppResult<Vec<ProcessedAgentWithId>> {
+    Ok(repo::select_processed_agent_data_list(page, size, sort, filter, pool).await?)
+}
+
+#[instrument(skip(pool))]
+pub async fn count_processed_agent_data(
+    filter: &ProcessedAgentFilter,
+    pool: &PgPool,
+) -> AppResult<u64> {
+    Ok(repo::count_processed_agent_data(filter, pool).await?)
+}
+
+#[instrument(skip(pool))]
+pub async fn search_processed_agent_data_in_area(
+    area: BoundingBox,
+    page: NonZeroU32,
+    size: NonZeroU8,
+    filter: &ProcessedAgentFilter,
+    pool: &PgPool,
+) -> AppResult<Vec<ProcessedAgentWithId>> {
+    Ok(repo::select_processed_agent_data_in_area(area, page, size, filter, pool).await?)
+}
+
+#[instrument(skip(pool))]
+pub async fn search_processed_agent_data_nearby(
+    circle: Circle,
     page: NonZeroU32,
     size: NonZeroU8,
+    filter: &ProcessedAgentFilter,
     pool: &PgPool,
 ) -> AppResult<Vec<ProcessedAgentWithId>> {
-    Ok(repo::select_processed_agent_data_list(page, size, pool).await?)
+    Ok(repo::select_processed_agent_data_nearby(circle, page, size, filter, pool).await?)
 }
 
 #[instrument(skip(pool, subs))]
 pub async fn update_processed_agent_data(
     id: ProcessedAgentId,
     data: ProcessedAgent,
+    if_match: Option<&VersionMatch>,
+    actor: Option<&str>,
     pool: &PgPool,
     subs: &Subscribers,
-) -> AppResult<bool> {
-    let updated = repo::update_processed_agent_data(id, &data, pool).await?;
-    if updated {
-        subs.broadcast(Message::Update { id, data: &data }).await?;
+) -> AppResult<Conditional<Option<Version>>> {
+    let mut tx = repo::begin(actor, pool).await?;
+    if !check_version(id, if_match, &mut tx).await? {
+        return Ok(Conditional::PreconditionFailed);
+    }
+    let Some(previous) = repo::lock_processed_agent_data(id, &mut *tx).await? else {
+        return Ok(Conditional::Applied(None));
+    };
+    let version = repo::update_processed_agent_data(id, &data, &mut *tx).await?;
+    tx.commit().await?;
+    if version.is_some() {
+        let (data, previous) = (&data, &previous);
+        subs.broadcast(Message::Update { id, data, previous })
+            .await?;
+    }
+
+    Ok(Conditional::Applied(version))
+}
+
+#[instrument(skip(pool, subs))]
+pub async fn patch_processed_agent_data(
+    id: ProcessedAgentId,
+    patch: ProcessedAgentPatch,
+    if_match: Option<&VersionMatch>,
+    actor: Option<&str>,
+    pool: &PgPool,
+    subs: &Subscribers,
+) -> AppResult<Conditional<Option<Versioned<ProcessedAgent>>>> {
+    let mut tx = repo::begin(actor, pool).await?;
+    if !check_version(id, if_match, &mut tx).await? {
+        return Ok(Conditional::PreconditionFailed);
+    }
+    let Some(previous) = repo::lock_processed_agent_data(id, &mut *tx).await? else {
+        return Ok(Conditional::Applied(None));
+    };
+    let updated = repo::patch_processed_agent_data(id, &patch, &mut *tx).await?;
+    tx.commit().await?;
+    if let Some(Versioned { data, .. }) = &updated {
+        let previous = &previous;
+        subs.broadcast(Message::Update { id, data, previous })
+            .await?;
     }
 
-    Ok(updated)
+    Ok(Conditional::Applied(updated))
 }
 
 #[instrument(skip(pool, subs))]
 pub async fn delete_processed_agent_data(
     id: ProcessedAgentId,
+    if_match: Option<&VersionMatch>,
+    actor: Option<&str>,
     pool: &PgPool,
     subs: &Subscribers,
+) -> AppResult<Conditional<()>> {
+    let mut tx = repo::begin(actor, pool).await?;
+    if !check_version(id, if_match, &mut tx).await? {
+        return Ok(Conditional::PreconditionFailed);
+    }
+    let deleted = repo::delete_processed_agent_data(id, &mut *tx).await?;
+    tx.commit().await?;
+    if let Some(data) = &deleted {
+        subs.broadcast(Message::Delete { id, data }).await?;
+    }
+
+    Ok(Conditional::Applied(()))
+}
+
+#[instrument(skip(pool, subs))]
+pub async fn delete_processed_agent_data_list(
+    selection: &ProcessedAgentSelection,
+    actor: Option<&str>,
+    pool: &PgPool,
+    subs: &Subscribers,
+) -> AppResult<u64> {
+    let mut tx = repo::begin(actor, pool).await?;
+    let deleted = repo::delete_processed_agent_data_list(selection, &mut *tx).await?;
+    tx.commit().await?;
+    let (ids, data): (Vec<_>, Vec<_>) = deleted.into_iter().unzip();
+    if !ids.is_empty() {
+        subs.broadcast(Message::Delete {
+            id: ids.as_slice(),
+            data: data.as_slice(),
+        })
+        .await?;
+    }
+
+    Ok(ids.len() as u64)
+}
+
+#[instrument(skip(pool))]
+pub async fn fetch_processed_agent_data_history(
+    id: ProcessedAgentId,
+    pool: &PgPool,
+) -> AppResult<Vec<ProcessedAgentRevision>> {
+    Ok(repo::select_processed_agent_data_history(id, pool).await?)
+}
+
+#[instrument(skip(pool))]
+pub async fn fetch_deleted_processed_agent_data_list(
+    page: NonZeroU32,
+    size: NonZeroU8,
+    filter: &ProcessedAgentFilter,
+    pool: &PgPool,
+) -> AppResult<Vec<ProcessedAgentWithId>> {
+    Ok(repo::select_deleted_processed_agent_data_list(page, size, filter, pool).await?)
+}
+
+#[instrument(skip(pool, subs))]
+pub async fn restore_processed_agent_data(
+    id: ProcessedAgentId,
+    actor: Option<&str>,
+    pool: &PgPool,
+    subs: &Subscribers,
+) -> AppResult<Option<ProcessedAgent>> {
+    let mut tx = repo::begin(actor, pool).await?;
+    let restored = repo::restore_processed_agent_data(id, &mut *tx).await?;
+    tx.commit().await?;
+    if let Some(data) = &restored {
+        subs.broadcast(Message::New { id, data }).await?;
+    }
+
+    Ok(restored)
+}
+
+#[instrument(skip(pool))]
+pub async fn purge_processed_agent_data(retention: Duration, pool: &PgPool) -> AppResult<u64> {
+    Ok(repo::purge_processed_agent_data(retention, pool).await?)
+}
+
+/// Periodically purge the processed agent data, that has been in the trash for longer than
+/// the retention period. Runs until the task is dropped.
+pub async fn purge_trash(trash: Trash, pool: PgPool) {
+    let mut interval = tokio::time::interval(trash.purge_interval());
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        match purge_processed_agent_data(trash.retention(), &pool).await {
+            Ok(0) => {}
+            Ok(purged) => tracing::info!("Purged {} processed agent data from the trash", purged),
+            Err(err) => tracing::error!("Failed to purge the trash: {}", err),
+        }
+    }
+}
+
+/// Periodically purge the expired responses to the requests with an idempotency key.
+/// Runs until the task is dropped.
+pub async fn purge_idempotency_keys(idempotency: Idempotency, pool: PgPool) {
+    let mut interval = tokio::time::interval(idempotency.purge_interval());
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        match repo::purge_idempotency_keys(idempotency.ttl(), &pool).await {
+            Ok(0) => {}
+            Ok(purged) => tracing::info!("Purged {} expired idempotency keys", purged),
+            Err(err) => tracing::error!("Failed to purge the idempotency keys: {}", err),
+        }
+    }
+}
+
+async fn store_processed_agent_data_list(
+    data: &[ProcessedAgent],
+    policy: DuplicatePolicy,
+    conn: &mut PgConnection,
+) -> sqlx::Result<Creation> {
+    if data.is_empty() {
+        return Ok(Creation::Stored(Vec::new()));
+    }
+
+    let stored = repo::insert_processed_agent_data_list(data, policy, conn).await?;
+    let duplicates: Vec<u32> = (0..)
+        .zip(&stored)
+        .filter(|(_, stored)| stored.duplicate)
+        .map(|(i, _)| i)
+        .collect();
+
+    Ok(
+        if policy == DuplicatePolicy::Reject && !duplicates.is_empty() {
+            Creation::Rejected(duplicates)
+        } else {
+            Creation::Stored(stored)
+        },
+    )
+}
+
+/// Notify about the created data and, when upserting, about the updated one.
+async fn notify_stored(
+    stored: &[Stored],
+    data: Vec<ProcessedAgent>,
+    policy: DuplicatePolicy,
+    subs: &Subscribers,
 ) -> AppResult<()> {
-    let deleted = repo::delete_processed_agent_data(id, pool).await?;
-    if deleted {
-        subs.broadcast::<ProcessedAgent>(Message::Delete { id })
-            .await?;
+    let (mut created_ids, mut created) = (Vec::new(), Vec::new());
+    let (mut updated_ids, mut updated, mut previous) = (Vec::new(), Vec::new(), Vec::new());
+    for (stored, data) in stored.iter().zip(data) {
+        if !stored.duplicate {
+            created_ids.push(stored.id);
+            created.push(data);
+        } else if policy == DuplicatePolicy::Upsert
+            // only the first of the same items in the list is written, be it inserted or updated
+            && !created_ids.contains(&stored.id)
+            && !updated_ids.contains(&stored.id)
+        {
+            updated_ids.push(stored.id);
+            // unless stored concurrently, so that only the updated data is known
+            previous.push(stored.previous.clone().unwrap_or_else(|| data.clone()));
+            updated.push(data);
+        }
+    }
+
+    match (created_ids.as_slice(), created.as_slice()) {
+        ([], []) => {}
+        ([id], [data]) => subs.broadcast(Message::New { id: *id, data }).await?,
+        (ids, data) => subs.broadcast(Message::New { id: ids, data }).await?,
+    }
+    match (
+        updated_ids.as_slice(),
+        updated.as_slice(),
+        previous.as_slice(),
+    ) {
+        ([], [], []) => {}
+        ([id], [data], [previous]) => {
+            subs.broadcast(Message::Update {
+                id: *id,
+                data,
+                previous,
+            })
+            .await?
+        }
+        (ids, data, previous) => {
+            subs.broadcast(Message::Update {
+                id: ids,
+                data,
+                previous,
+            })
+            .await?
+        }
     }
 
     Ok(())
 }
+
+/// Lock the data until the end of the transaction and check, that its current version
+/// is one of the expected ones, if any are.
+async fn check_version(
+    id: ProcessedAgentId,
+    if_match: Option<&VersionMatch>,
+    conn: &mut PgConnection,
+) -> AppResult<bool> {
+    let Some(if_match) = if_match else {
+        return Ok(true);
+    };
+    let version = repo::lock_processed_agent_data_version(id, conn).await?;
+
+    Ok(version.is_some_and(|version| if_match.matches(version)))
+}
@@ -0,0 +1,558 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Debug,
+    num::NonZeroU8,
+    sync::{Arc, RwLock},
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use tokio::sync::Mutex;
+use tracing::instrument;
+use utoipa::ToSchema;
+
+use crate::{
+    control::ws::{Message, Subscribers},
+    data::{repo, Accelerometer, Agent, AgentId, ProcessedAgent, RoadState, Validate, Validator},
+    error::AppResult,
+};
+
+/// Standard gravity, in m/s^2
+const GRAVITY: f64 = 9.80665;
+
+/// Derives the state of the road from the raw agent readings.
+pub trait Classifier: Debug + Send + Sync {
+    /// Number of the consecutive readings of an agent, that are classified together
+    fn window_size(&self) -> usize {
+        1
+    }
+
+    /// Classify the last of the consecutive readings of an agent, ordered by time
+    fn classify(&self, window: &[&Accelerometer]) -> RoadState;
+}
+
+/// Deviations of the acceleration along an axis from its value at rest,
+/// from which the road is considered to have pits or bumps, in m/s^2.
+/// The road drops under the pits and rises under the bumps.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize, ToSchema)]
+pub struct Thresholds {
+    #[schema(example = 2.0)]
+    pub small_pits: f64,
+    #[schema(example = 5.0)]
+    pub large_pits: f64,
+    #[schema(example = 3.0)]
+    pub bump: f64,
+}
+
+/// Thresholds for a type of vehicle, on the axes to take into account.
+///
+/// The most severe of the states, derived from each of the axes, wins.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize, ToSchema)]
+pub struct Profile {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub x: Option<Thresholds>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub y: Option<Thresholds>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub z: Option<Thresholds>,
+}
+
+/// Parameters of the threshold-based road-state classification.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, ToSchema)]
+pub struct Parameters {
+    /// Name of the profile in use
+    #[schema(example = "car")]
+    pub profile: String,
+    /// Number of the consecutive readings of an agent, whose accelerometer values are averaged
+    #[schema(value_type = u8, minimum = 1, example = 1)]
+    pub window_size: NonZeroU8,
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+/// Classification parameters in use, that can be replaced at runtime.
+#[derive(Debug)]
+pub struct Classification {
+    current: RwLock<Arc<Parameters>>,
+    /// Held while the parameters are persisted and replaced, to keep the order of the updates
+    updating: Mutex<()>,
+}
+
+impl Classification {
+    pub fn new(parameters: Parameters) -> Self {
+        Self {
+            current: RwLock::new(Arc::new(parameters)),
+            updating: Mutex::new(()),
+        }
+    }
+
+    /// Snapshot of the parameters in use, unaffected by the later replacements
+    pub fn current(&self) -> Arc<Parameters> {
+        Arc::clone(&self.current.read().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    fn replace(&self, parameters: Parameters) {
+        *self.current.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(parameters);
+    }
+}
+
+impl Thresholds {
+    fn classify(&self, deviation: f64) -> RoadState {
+        if deviation <= -self.large_pits {
+            RoadState::LargePits
+        } else if deviation <= -self.small_pits {
+            RoadState::SmallPits
+        } else if deviation >= self.bump {
+            RoadState::Bump
+        } else {
+            RoadState::Normal
+        }
+    }
+}
+
+impl Classifier for Parameters {
+    fn window_size(&self) -> usize {
+        self.window_size.get().into()
+    }
+
+    fn classify(&self, window: &[&Accelerometer]) -> RoadState {
+        let Some(profile) = self.profiles.get(&self.profile) else {
+            return RoadState::Normal;
+        };
+        let mean = |axis: fn(&Accelerometer) -> f64| {
+            window
+                .iter()
+                .map(|&accelerometer| axis(accelerometer))
+                .sum::<f64>()
+                / window.len() as f64
+        };
+
+        [
+            (profile.x, mean(|a| a.x)),
+            (profile.y, mean(|a| a.y)),
+            (profile.z, mean(|a| a.z) - GRAVITY),
+        ]
+        .into_iter()
+        .filter_map(|(thresholds, deviation)| Some(thresholds?.classify(deviation)))
+        .max_by_key(|&state| severity(state))
+        .unwrap_or(RoadState::Normal)
+    }
+}
+
+impl Validate for Thresholds {
+    fn validate(&self, validator: &mut Validator) {
+        for (name, value) in [
+            ("small_pits", self.small_pits),
+            ("large_pits", self.large_pits),
+            ("bump", self.bump),
+        ] {
+            validator.field(name, |v| {
+                v.check(
+                    value.is_finite() && value > 0.0,
+                    "must be a positive number",
+                );
+            });
+        }
+        validator.field("large_pits", |v| {
+            v.check(
+                self.large_pits >= self.small_pits,
+                "must not be less than `small_pits`",
+            );
+        });
+    }
+}
+
+impl Validate for Profile {
+    fn validate(&self, validator: &mut Validator) {
+        for (name, thresholds) in [("x", &self.x), ("y", &self.y), ("z", &self.z)] {
+            if let Some(thresholds) = thresholds {
+                validator.field(name, |v| thresholds.validate(v));
+            }
+        }
+    }
+}
+
+impl Validate for Parameters {
+    fn validate(&self, validator: &mut Validator) {
+        validator.field("profile", |v| {
+            v.check(
+                self.profiles.contains_key(&self.profile),
+                "must be one of the `profiles`",
+            );
+        });
+        validator.field("profiles", |v| {
+            for (name, profile) in &self.profiles {
+                v.field(name, |v| profile.validate(v));
+            }
+        });
+    }
+}
+
+/// Attach the road state, derived by the `classifier`, to each of the readings.
+///
+/// The windows span the readings of the same agent among the given ones, preceded by
+/// the stored ones earlier than all of them, so that they agree with the reclassification.
+#[instrument(skip_all)]
+pub async fn classify(
+    data: Vec<Agent>,
+    classifier: &dyn Classifier,
+    pool: &PgPool,
+) -> AppResult<Vec<ProcessedAgent>> {
+    let preceding = match classifier.window_size() {
+        0 | 1 => Vec::new(),
+        window_size => {
+            let mut earliest = BTreeMap::new();
+            for agent in &data {
+                earliest
+                    .entry(agent.agent_id)
+                    .and_modify(|timestamp: &mut DateTime<Utc>| {
+                        *timestamp = agent.timestamp.min(*timestamp)
+                    })
+                    .or_insert(agent.timestamp);
+            }
+            let (agent_ids, before): (Vec<_>, Vec<_>) = earliest.into_iter().unzip();
+            let count = (window_size - 1).try_into().unwrap_or(u32::MAX);
+            repo::select_preceding_processed_agent_data(&agent_ids, &before, count, pool).await?
+        }
+    };
+    let readings: Vec<_> = preceding
+        .iter()
+        .map(|data| &data.agent_data)
+        .chain(&data)
+        .map(|agent| (agent.agent_id, agent.timestamp, &agent.accelerometer))
+        .collect();
+    let road_states = road_states(&readings, classifier);
+
+    Ok(data
+        .into_iter()
+        .zip(road_states.into_iter().skip(preceding.len()))
+        .map(|(agent_data, road_state)| ProcessedAgent {
+            agent_data,
+            road_state,
+        })
+        .collect())
+}
+
+/// Load the parameters, last set at runtime, or the `configured` ones if they never were.
+#[instrument(skip(pool))]
+pub async fn load_parameters(configured: Parameters, pool: &PgPool) -> AppResult<Parameters> {
+    let Some(parameters) = repo::select_classification_parameters(pool).await? else {
+        return Ok(configured);
+    };
+
+    Ok(serde_json::from_str(&parameters)?)
+}
+
+/// Persist the `parameters` and use them from now on.
+#[instrument(skip(classification, pool))]
+pub async fn update_parameters(
+    classification: &Classification,
+    parameters: Parameters,
+    actor: Option<&str>,
+    pool: &PgPool,
+) -> AppResult<()> {
+    let serialized = serde_json::to_string(&parameters)?;
+    let _updating = classification.updating.lock().await;
+    repo::upsert_classification_parameters(&serialized, actor, pool).await?;
+    classification.replace(parameters);
+
+    Ok(())
+}
+
+/// Re-derive the road state of all the stored processed agent data, agent by agent,
+/// and notify about the changed one.
+///
+/// Returns the number of the processed agent data, whose road state changed.
+#[instrument(skip(classifier, subs, pool))]
+pub async fn reclassify_processed_agent_data(
+    classifier: &dyn Classifier,
+    actor: Option<&str>,
+    subs: &Subscribers,
+    pool: &PgPool,
+) -> AppResult<u64> {
+    let mut reclassified = 0;
+    for agent_id in repo::select_agent_ids(pool).await? {
+        let mut tx = repo::begin(actor, pool).await?;
+        let stored = repo::lock_agent_processed_agent_data(agent_id, &mut *tx).await?;
+        let readings: Vec<_> = stored
+            .iter()
+            .map(|(_, data)| {
+                let agent = &data.agent_data;
+                (agent.agent_id, agent.timestamp, &agent.accelerometer)
+            })
+            .collect();
+        let (ids, road_states): (Vec<_>, Vec<_>) = stored
+            .iter()
+            .zip(road_states(&readings, classifier))
+            .filter(|((_, data), road_state)| data.road_state != *road_state)
+            .map(|((id, _), road_state)| (*id, road_state))
+            .unzip();
+        if ids.is_empty() {
+            continue;
+        }
+
+        let updated = repo::update_road_states(&ids, &road_states, &mut *tx).await?;
+        tx.commit().await?;
+        reclassified += updated.len() as u64;
+
+        let mut stored: HashMap<_, _> = stored.into_iter().collect();
+        let (mut ids, mut data, mut previous) = (Vec::new(), Vec::new(), Vec::new());
+        for (id, updated) in updated {
+            if let Some(stored) = stored.remove(&id) {
+                ids.push(id);
+                data.push(updated);
+                previous.push(stored);
+            }
+        }
+        subs.broadcast(Message::Update {
+            id: ids.as_slice(),
+            data: data.as_slice(),
+            previous: previous.as_slice(),
+        })
+        .await?;
+    }
+
+    Ok(reclassified)
+}
+
+/// Road states of the readings, each classified in the window of the preceding readings
+/// of the same agent
+fn road_states(
+    readings: &[(AgentId, DateTime<Utc>, &Accelerometer)],
+    classifier: &dyn Classifier,
+) -> Vec<RoadState> {
+    let mut order: Vec<usize> = (0..readings.len()).collect();
+    order.sort_by_key(|&i| (readings[i].0, readings[i].1, i));
+
+    let window_size = classifier.window_size().max(1);
+    let mut road_states = vec![RoadState::Normal; readings.len()];
+    let mut window = Vec::with_capacity(window_size);
+    for (position, &i) in order.iter().enumerate() {
+        let start = position.saturating_sub(window_size - 1);
+        window.clear();
+        window.extend(
+            order[start..=position]
+                .iter()
+                .filter(|&&j| readings[j].0 == readings[i].0)
+                .map(|&j| readings[j].2),
+        );
+        road_states[i] = classifier.classify(&window);
+    }
+
+    road_states
+}
+
+/// Order of the road states by how much attention they require
+fn severity(state: RoadState) -> u8 {
+    match state {
+        RoadState::Normal | RoadState::Unknown => 0,
+        RoadState::Bump => 1,
+        RoadState::SmallPits => 2,
+        RoadState::LargePits => 3,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone};
+    use serde_json::json;
+
+    use super::*;
+    use crate::data::validate;
+
+    fn parameters(window_size: u8) -> Parameters {
+        serde_json::from_value(json!({
+            "profile": "car",
+            "window_size": window_size,
+            "profiles": {
+                "car": {
+                    "z": { "small_pits": 2.0, "large_pits": 5.0, "bump": 3.0 },
+                },
+            },
+        }))
+        .unwrap()
+    }
+
+    fn agent_id(value: i32) -> AgentId {
+        serde_json::from_value(json!(value)).unwrap()
+    }
+
+    fn at(seconds: i64) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 3, 1, 0, 0, 0).unwrap() + Duration::seconds(seconds)
+    }
+
+    /// Accelerometer reading, deviating from the gravity by `deviation` along the z axis
+    fn reading(deviation: f64) -> Accelerometer {
+        Accelerometer {
+            x: 0.0,
+            y: 0.0,
+            z: GRAVITY + deviation,
+        }
+    }
+
+    fn limits() -> crate::config::Validation {
+        serde_json::from_value(json!({
+            "max_clock_skew_secs": 60,
+            "max_acceleration": 160.0,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn thresholds_are_inclusive() {
+        let classifier = parameters(1);
+        let classify = |deviation| classifier.classify(&[&reading(deviation)]);
+
+        assert_eq!(classify(-1.9), RoadState::Normal);
+        assert_eq!(classify(-2.0), RoadState::SmallPits);
+        assert_eq!(classify(-5.0), RoadState::LargePits);
+        assert_eq!(classify(2.9), RoadState::Normal);
+        assert_eq!(classify(3.0), RoadState::Bump);
+    }
+
+    #[test]
+    fn most_severe_axis_wins() {
+        let mut classifier = parameters(1);
+        let thresholds = Thresholds {
+            small_pits: 2.0,
+            large_pits: 5.0,
+            bump: 3.0,
+        };
+        let profile = classifier.profiles.get_mut("car").unwrap();
+        profile.x = Some(thresholds);
+        profile.y = Some(thresholds);
+        let accelerometer = Accelerometer {
+            x: 3.5,
+            y: -2.5,
+            z: GRAVITY - 6.0,
+        };
+
+        assert_eq!(classifier.classify(&[&accelerometer]), RoadState::LargePits);
+    }
+
+    #[test]
+    fn missing_profile_is_normal() {
+        let mut classifier = parameters(1);
+        classifier.profile = "truck".into();
+
+        assert_eq!(classifier.classify(&[&reading(-6.0)]), RoadState::Normal);
+    }
+
+    #[test]
+    fn window_is_averaged() {
+        let (large_pits, level) = (reading(-6.0), reading(0.0));
+        let readings = [
+            (agent_id(1), at(0), &large_pits),
+            (agent_id(1), at(1), &level),
+            (agent_id(1), at(2), &level),
+        ];
+
+        assert_eq!(
+            road_states(&readings, &parameters(2)),
+            [
+                RoadState::LargePits,
+                RoadState::SmallPits,
+                RoadState::Normal
+            ]
+        );
+    }
+
+    #[test]
+    fn readings_are_windowed_in_time_order() {
+        let (large_pits, level) = (reading(-6.0), reading(0.0));
+        let readings = [
+            (agent_id(1), at(2), &level),
+            (agent_id(1), at(0), &level),
+            (agent_id(1), at(1), &large_pits),
+        ];
+
+        assert_eq!(
+            road_states(&readings, &parameters(2)),
+            [
+                RoadState::SmallPits,
+                RoadState::Normal,
+                RoadState::SmallPits
+            ]
+        );
+    }
+
+    #[test]
+    fn interleaved_agents_never_share_a_window() {
+        let (large_pits, level) = (reading(-6.0), reading(0.0));
+        let readings = [
+            (agent_id(1), at(0), &large_pits),
+            (agent_id(2), at(1), &level),
+            (agent_id(1), at(2), &large_pits),
+            (agent_id(2), at(3), &level),
+            (agent_id(1), at(4), &large_pits),
+            (agent_id(2), at(5), &level),
+        ];
+
+        assert_eq!(
+            road_states(&readings, &parameters(3)),
+            [
+                RoadState::LargePits,
+                RoadState::Normal,
+                RoadState::LargePits,
+                RoadState::Normal,
+                RoadState::LargePits,
+                RoadState::Normal,
+            ]
+        );
+    }
+
+    #[test]
+    fn posted_readings_follow_the_stored_ones() {
+        let (large_pits, level) = (reading(-6.0), reading(0.0));
+        // as put together by `classify`: the stored readings, then the posted ones,
+        // of which the first is recorded at the same moment as the last stored one
+        let readings = [
+            (agent_id(1), at(0), &level),
+            (agent_id(1), at(1), &large_pits),
+            (agent_id(1), at(1), &level),
+            (agent_id(1), at(2), &level),
+        ];
+
+        let road_states = road_states(&readings, &parameters(2));
+        assert_eq!(
+            road_states[2..],
+            [RoadState::SmallPits, RoadState::Normal][..]
+        );
+    }
+
+    #[test]
+    fn valid_parameters_pass() {
+        assert_eq!(validate(&parameters(1), limits()), Ok(()));
+    }
+
+    #[test]
+    fn unknown_profile_is_rejected() {
+        let mut parameters = parameters(1);
+        parameters.profile = "truck".into();
+
+        let errors = validate(&parameters, limits()).unwrap_err().errors;
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].field, "/profile");
+    }
+
+    #[test]
+    fn inconsistent_thresholds_are_rejected() {
+        let mut parameters = parameters(1);
+        parameters.profiles.get_mut("car").unwrap().z = Some(Thresholds {
+            small_pits: 4.0,
+            large_pits: 3.0,
+            bump: f64::NAN,
+        });
+
+        let fields: Vec<_> = validate(&parameters, limits())
+            .unwrap_err()
+            .errors
+            .into_iter()
+            .map(|error| error.field)
+            .collect();
+        assert_eq!(
+            fields,
+            ["/profiles/car/z/bump", "/profiles/car/z/large_pits"]
+        );
+    }
+}
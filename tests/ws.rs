@@ -0,0 +1,365 @@
+use std::{
+    future::Future,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use actix_web::{web, App, HttpServer};
+use lab2::{
+    config::WebSocket,
+    control::ws::{self, Message},
+    data::{ProcessedAgent, ProcessedAgentId},
+};
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xA;
+
+/// Serve the WebSocket endpoint alone on a random port
+fn serve() -> (SocketAddr, web::Data<ws::Subscribers>) {
+    serve_with(json!({}))
+}
+
+/// Serve the WebSocket endpoint with some of the default settings overridden
+fn serve_with(overrides: Value) -> (SocketAddr, web::Data<ws::Subscribers>) {
+    let mut settings = json!({
+        "queue_capacity": 16,
+        "overflow": "drop_oldest",
+        "event_log_capacity": 2,
+        "heartbeat_interval_secs": 60,
+        "max_missed_pongs": 2,
+    });
+    settings
+        .as_object_mut()
+        .unwrap()
+        .extend(overrides.as_object().unwrap().clone());
+    let settings: WebSocket = serde_json::from_value(settings).unwrap();
+    let subscribers = web::Data::new(ws::Subscribers::new(settings));
+
+    let server = HttpServer::new({
+        let subscribers = subscribers.clone();
+        move || {
+            App::new()
+                .app_data(subscribers.clone())
+                .service(ws::ws_endpoint)
+        }
+    })
+    .workers(1)
+    .bind(("127.0.0.1", 0))
+    .unwrap();
+    let addr = server.addrs()[0];
+    actix_web::rt::spawn(server.run());
+
+    (addr, subscribers)
+}
+
+/// Open a WebSocket connection
+async fn connect(addr: SocketAddr, path: &str) -> TcpStream {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream
+        .write_all(
+            format!(
+                "GET {path} HTTP/1.1\r\n\
+                 Host: {addr}\r\n\
+                 Upgrade: websocket\r\n\
+                 Connection: Upgrade\r\n\
+                 Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                 Sec-WebSocket-Version: 13\r\n\r\n"
+            )
+            .as_bytes(),
+        )
+        .await
+        .unwrap();
+
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        head.push(stream.read_u8().await.unwrap());
+    }
+    assert!(head.starts_with(b"HTTP/1.1 101"), "upgrade refused");
+
+    stream
+}
+
+/// Send a frame, masked as the clients are required to
+async fn send(stream: &mut TcpStream, opcode: u8, payload: &[u8], masked: bool) {
+    const MASK: [u8; 4] = [0x12, 0x34, 0x56, 0x78];
+
+    assert!(payload.len() < 126);
+    let mut frame = vec![0x80 | opcode, payload.len() as u8];
+    if masked {
+        frame[1] |= 0x80;
+        frame.extend(MASK);
+        frame.extend(payload.iter().zip(MASK.iter().cycle()).map(|(b, m)| b ^ m));
+    } else {
+        frame.extend(payload);
+    }
+    stream.write_all(&frame).await.unwrap();
+}
+
+/// Read the opcode and the payload of the next frame
+async fn receive(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+    tokio::time::timeout(Duration::from_secs(5), read_frame(stream))
+        .await
+        .expect("no frame received in time")
+}
+
+async fn read_frame(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+    let opcode = stream.read_u8().await.unwrap() & 0x0f;
+    let len = match stream.read_u8().await.unwrap() & 0x7f {
+        126 => stream.read_u16().await.unwrap() as usize,
+        127 => stream.read_u64().await.unwrap() as usize,
+        len => len as usize,
+    };
+    let mut payload = vec![0; len];
+    stream.read_exact(&mut payload).await.unwrap();
+
+    (opcode, payload)
+}
+
+/// Read the next notification
+async fn notification(stream: &mut TcpStream) -> Value {
+    let (opcode, payload) = receive(stream).await;
+    assert_eq!(opcode, BINARY);
+    serde_json::from_slice(&payload).unwrap()
+}
+
+/// Notify about new processed agent data with the `id`
+async fn broadcast(subscribers: &ws::Subscribers, id: i32) {
+    let id: ProcessedAgentId = serde_json::from_value(json!(id)).unwrap();
+    let data = processed_agent("NORMAL");
+    subscribers
+        .broadcast(Message::New { id, data: &data })
+        .await
+        .unwrap();
+}
+
+fn processed_agent(road_state: &str) -> ProcessedAgent {
+    serde_json::from_value(json!({
+        "agent_id": 1,
+        "accelerometer": { "x": 0.0, "y": 0.0, "z": 9.8 },
+        "gps": { "latitude": 50.45, "longitude": 30.52 },
+        "timestamp": "2024-03-01T10:00:00Z",
+        "road_state": road_state,
+    }))
+    .unwrap()
+}
+
+async fn eventually<F: Future<Output = bool>>(condition: impl Fn() -> F) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !condition().await {
+        assert!(Instant::now() < deadline, "condition not met in time");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+#[actix_web::test]
+async fn subscriber_is_removed_on_normal_close() {
+    let (addr, subscribers) = serve();
+    let mut stream = connect(addr, "/ws").await;
+
+    send(&mut stream, CLOSE, &1000u16.to_be_bytes(), true).await;
+
+    let (opcode, payload) = receive(&mut stream).await;
+    assert_eq!(opcode, CLOSE);
+    assert_eq!(payload[..2], 1000u16.to_be_bytes());
+    eventually(|| async { subscribers.is_empty() }).await;
+}
+
+#[actix_web::test]
+async fn subscriber_is_removed_on_protocol_error() {
+    let (addr, subscribers) = serve();
+    let mut stream = connect(addr, "/ws").await;
+
+    send(&mut stream, TEXT, br#"{"type":"unsubscribe"}"#, false).await;
+
+    let (opcode, payload) = receive(&mut stream).await;
+    assert_eq!(opcode, CLOSE);
+    assert_eq!(payload[..2], 1002u16.to_be_bytes());
+    drop(stream);
+    eventually(|| async { subscribers.is_empty() }).await;
+}
+
+#[actix_web::test]
+async fn subscriber_is_removed_on_disconnect_without_close() {
+    let (addr, subscribers) = serve();
+    let stream = connect(addr, "/ws").await;
+    let other = connect(addr, "/ws").await;
+    eventually(|| async { subscribers.len() == 2 }).await;
+
+    // the connection is closed without the close frame
+    drop(stream);
+
+    eventually(|| async { subscribers.len() == 1 }).await;
+    drop(other);
+    eventually(|| async { subscribers.is_empty() }).await;
+}
+
+#[actix_web::test]
+async fn subscriber_is_removed_when_client_is_gone() {
+    let (addr, subscribers) = serve_with(json!({
+        "heartbeat_interval_secs": 1,
+        "max_missed_pongs": 1,
+    }));
+    // neither the connection is closed, nor anything is read from or sent to it anymore,
+    // as if the client lost the network
+    let _stream = connect(addr, "/ws").await;
+    eventually(|| async { subscribers.len() == 1 }).await;
+
+    eventually(|| async { subscribers.is_empty() }).await;
+}
+
+#[actix_web::test]
+async fn missed_notifications_are_replayed_on_reconnect() {
+    let (addr, subscribers) = serve();
+    let mut stream = connect(addr, "/ws").await;
+    eventually(|| async { subscribers.len() == 1 }).await;
+
+    broadcast(&subscribers, 1).await;
+    broadcast(&subscribers, 2).await;
+    let last = notification(&mut stream).await;
+    assert_eq!(last["seq"], 1);
+    assert_eq!(last["epoch"], subscribers.epoch());
+    drop(stream);
+    broadcast(&subscribers, 3).await;
+
+    let path = format!("/ws?since=1&epoch={}", last["epoch"].as_str().unwrap());
+    let mut stream = connect(addr, &path).await;
+    for (seq, id) in [(2, 2), (3, 3)] {
+        let notification = notification(&mut stream).await;
+        assert_eq!(notification["seq"], seq);
+        assert_eq!(notification["id"], id);
+    }
+    // the live notifications follow the missed ones
+    broadcast(&subscribers, 4).await;
+    assert_eq!(notification(&mut stream).await["seq"], 4);
+}
+
+#[actix_web::test]
+async fn resync_is_requested_when_missed_notifications_are_not_kept() {
+    let (addr, subscribers) = serve();
+    for id in 1..=3 {
+        broadcast(&subscribers, id).await;
+    }
+
+    // only the last 2 notifications are kept
+    let epoch = subscribers.epoch();
+    for since in [0, 4] {
+        let mut stream = connect(addr, &format!("/ws?since={since}&epoch={epoch}")).await;
+        assert_eq!(
+            notification(&mut stream).await,
+            json!({ "kind": "resync", "epoch": epoch, "seq": 3 })
+        );
+    }
+
+    let mut stream = connect(addr, &format!("/ws?since=1&epoch={epoch}")).await;
+    assert_eq!(notification(&mut stream).await["seq"], 2);
+}
+
+#[actix_web::test]
+async fn resync_is_requested_after_restart() {
+    let (_, before_restart) = serve();
+    let (addr, subscribers) = serve();
+    for id in 1..=2 {
+        broadcast(&subscribers, id).await;
+    }
+
+    // the same sequence numbers are sent by the restarted server
+    let path = format!("/ws?since=1&epoch={}", before_restart.epoch());
+    let mut stream = connect(addr, &path).await;
+    assert_eq!(
+        notification(&mut stream).await,
+        json!({ "kind": "resync", "epoch": subscribers.epoch(), "seq": 2 })
+    );
+
+    let mut stream = connect(addr, "/ws?since=1").await;
+    assert_eq!(notification(&mut stream).await["kind"], "resync");
+}
+
+#[actix_web::test]
+async fn update_is_sent_when_previous_data_matches_filter() {
+    let (addr, subscribers) = serve();
+    let mut stream = connect(addr, "/ws").await;
+    let subscribe = br#"{"type":"subscribe","filter":{"road_states":["LARGE_PITS"]}}"#;
+    send(&mut stream, TEXT, subscribe, true).await;
+    assert_eq!(receive(&mut stream).await.0, TEXT);
+
+    let id: ProcessedAgentId = serde_json::from_value(json!(1)).unwrap();
+    let (previous, data) = (processed_agent("LARGE_PITS"), processed_agent("NORMAL"));
+    let update = Message::Update {
+        id,
+        data: &data,
+        previous: &previous,
+    };
+    subscribers.broadcast(update).await.unwrap();
+
+    let notification = notification(&mut stream).await;
+    assert_eq!(notification["kind"], "update");
+    assert_eq!(notification["data"]["road_state"], "NORMAL");
+}
+
+#[actix_web::test]
+async fn session_is_closed_after_missed_pongs() {
+    let (addr, subscribers) = serve_with(json!({
+        "heartbeat_interval_secs": 1,
+        "max_missed_pongs": 1,
+    }));
+    let mut stream = connect(addr, "/ws").await;
+
+    let (opcode, payload) = receive(&mut stream).await;
+    assert_eq!(opcode, PING);
+    send(&mut stream, PONG, &payload, true).await;
+    // the answered ping is not counted as missed
+    assert_eq!(receive(&mut stream).await.0, PING);
+
+    let (opcode, payload) = receive(&mut stream).await;
+    assert_eq!(opcode, CLOSE);
+    assert_eq!(payload[..2], 1008u16.to_be_bytes());
+    assert_eq!(&payload[2..], b"Missed too many pongs");
+    eventually(|| async { subscribers.is_empty() }).await;
+}
+
+#[actix_web::test]
+async fn passive_session_is_kept() {
+    let (addr, subscribers) = serve_with(json!({
+        "heartbeat_interval_secs": 1,
+        "max_missed_pongs": 1,
+    }));
+    let mut stream = connect(addr, "/ws").await;
+
+    // a listening client sends no commands, it only answers the pings
+    for _ in 0..4 {
+        let (opcode, payload) = receive(&mut stream).await;
+        assert_eq!(opcode, PING);
+        send(&mut stream, PONG, &payload, true).await;
+    }
+    assert_eq!(subscribers.len(), 1);
+}
+
+#[actix_web::test]
+async fn idle_session_is_closed_after_configured_timeout() {
+    let (addr, subscribers) = serve_with(json!({
+        "heartbeat_interval_secs": 1,
+        "max_missed_pongs": 10,
+        "idle_timeout_secs": 2,
+    }));
+    let mut stream = connect(addr, "/ws").await;
+
+    // the pings are answered, as the browsers do on their own
+    let (opcode, payload) = loop {
+        match receive(&mut stream).await {
+            (PING, payload) => send(&mut stream, PONG, &payload, true).await,
+            frame => break frame,
+        }
+    };
+    assert_eq!(opcode, CLOSE);
+    assert_eq!(payload[..2], 1008u16.to_be_bytes());
+    assert_eq!(&payload[2..], b"Received nothing but pongs for too long");
+    eventually(|| async { subscribers.is_empty() }).await;
+}